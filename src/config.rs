@@ -1,13 +1,30 @@
 use std::{
-  collections::{HashMap, VecDeque},
+  collections::{BTreeMap, HashMap, VecDeque},
+  fs,
+  os::unix::fs::OpenOptionsExt,
   path::{Path, PathBuf},
+  process::{Command, Stdio},
+  sync::atomic::{AtomicUsize, Ordering},
+  time::{Duration, Instant},
 };
 
-use colored::Colorize;
 use path_clean::PathClean;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::nix_shell;
+use crate::{
+  ShellContext, clear_env, container_argv, container_shell, display_command,
+  dotenv,
+  error::AssembleError,
+  escape_bash_string,
+  events::Event,
+  exec_argv, is_env_name, nix_gc_root, nix_shell, on_path,
+  run::{
+    CommandOutcome, RunOptions, Status, Verbosity, execute, execute_captured,
+  },
+  shell_script,
+  state::gc_root,
+  with_limits, with_pty,
+};
 
 fn substitute_args(cmd: &str, args: &HashMap<String, String>) -> String {
   let mut result = cmd.to_string();
@@ -17,9 +34,27 @@ fn substitute_args(cmd: &str, args: &HashMap<String, String>) -> String {
   result
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+/// Fills the `{{...}}` tokens of a step's `cwd`, as in its commands.
+pub fn expand_cwd(cwd: &Path, vars: &HashMap<String, String>) -> PathBuf {
+  PathBuf::from(substitute_args(&cwd.to_string_lossy(), vars))
+}
+
+/// Returns the first `{{...}}` token left in `cmd` after substitution.
+pub fn find_unresolved(cmd: &str) -> Option<&str> {
+  let start = cmd.find("{{")?;
+  let end = cmd[start..]
+    .find("}}")
+    .map(|end| start + end + 2)
+    .unwrap_or(cmd.len());
+  Some(&cmd[start..end])
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Cmds {
+  /// `run: ~`, for a step that only sets up its deps. `run: []` works the
+  /// same.
+  None,
   Single(String),
   Many(Vec<String>),
 }
@@ -29,51 +64,76 @@ impl Cmds {
     &self,
     path: &PathBuf,
     deps: Option<T>,
-    project_name: &str,
-    project_dir: &Path,
+    inherit: bool,
+    ctx: &ShellContext,
   ) -> std::process::Command
   where
     T: Iterator<Item = &'a String>,
   {
-    match self {
-      Cmds::Single(cmd) => nix_shell(
-        path,
-        deps,
-        &[cmd.to_string()],
-        true,
-        project_name,
-        project_dir,
-      ),
-      Cmds::Many(cmds) => {
-        nix_shell(path, deps, cmds, true, project_name, project_dir)
-      }
-    }
+    nix_shell(path, deps, &self.to_vec(), inherit, ctx)
   }
 
   pub fn to_vec(&self) -> Vec<String> {
     match self {
+      Cmds::None => Vec::new(),
       Cmds::Single(cmd) => vec![cmd.to_string()],
       Cmds::Many(cmds) => cmds.clone(),
     }
   }
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+/// A task argument: a single value, or a list whose items are substituted
+/// joined by spaces (`targets: [a, b]` becomes `a b`).
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Arg {
+  Single(String),
+  Many(Vec<String>),
+}
+
+impl Arg {
+  pub fn joined(&self) -> String {
+    match self {
+      Arg::Single(value) => value.clone(),
+      Arg::Many(values) => values.join(" "),
+    }
+  }
+}
+
+/// Reads a project's `with` map, which takes the same values as a step's,
+/// joining lists up front.
+fn joined_args<'de, D>(
+  deserializer: D,
+) -> Result<HashMap<String, String>, D::Error>
+where
+  D: serde::Deserializer<'de>,
+{
+  let args = HashMap::<String, Arg>::deserialize(deserializer)?;
+  Ok(
+    args
+      .into_iter()
+      .map(|(key, value)| (key, value.joined()))
+      .collect(),
+  )
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ExecTask {
   task: String,
   #[serde(default)]
-  with: HashMap<String, String>,
+  with: HashMap<String, Arg>,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 #[serde(untagged)]
 pub enum Exec {
   Run { run: Cmds },
   Task(ExecTask),
+  Argv { argv: Vec<String> },
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Step {
   #[serde(flatten)]
   exec: Exec,
@@ -81,135 +141,1430 @@ pub struct Step {
   pub deps: Vec<String>,
   #[serde(default)]
   pub cwd: Option<PathBuf>,
+  /// When false, an `argv` step is executed directly instead of through a
+  /// shell, so its arguments are never subject to shell parsing.
+  #[serde(default = "default_shell")]
+  pub shell: bool,
+  /// Store the step's trimmed stdout under this name so later steps in the
+  /// same phase can use it as `{{NAME}}`.
+  #[serde(default)]
+  pub capture: Option<String>,
+  /// On a terminal, erase the step's output once it succeeds.
+  #[serde(default)]
+  pub clear_on_success: bool,
+  /// Variables exported to the step's commands.
+  #[serde(default)]
+  pub env: HashMap<String, String>,
+  /// Run the step's commands in a container of this image (with podman, or
+  /// docker) instead of a nix shell. Can't be combined with `deps`.
+  #[serde(default)]
+  pub container: Option<String>,
+  /// How many times to rerun a command of this step that fails.
+  #[serde(default)]
+  pub retries: u32,
+  /// Exit codes worth retrying, such as 75 (`EX_TEMPFAIL`). Other failures
+  /// fail the step immediately. When empty, any failure is retried.
+  #[serde(default)]
+  pub retry_on: Vec<i32>,
+  /// How long to wait before each retry (such as `10s`).
+  #[serde(default, with = "humantime_serde")]
+  pub retry_delay: Option<Duration>,
+  /// Resource caps for the step's commands.
+  #[serde(default)]
+  pub limits: Option<Limits>,
+  /// Only run the step if this shell command succeeds.
+  #[serde(default, rename = "if")]
+  pub run_if: Option<String>,
+  /// Skip the step if this shell command succeeds.
+  #[serde(default)]
+  pub unless: Option<String>,
+  /// Kill a command of this step that runs longer than this (such as `30s`
+  /// or `5m`), failing the step.
+  #[serde(default, with = "humantime_serde")]
+  pub timeout: Option<Duration>,
+  /// Run the step's nix shell with `--pure`, as if the project set `pure`.
+  #[serde(default)]
+  pub pure: bool,
+}
+
+fn default_shell() -> bool {
+  true
+}
+
+/// Resource caps enforced by running a command in a transient systemd scope
+/// (`systemd-run --user --scope`), so they only work on Linux with a user
+/// systemd instance.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Limits {
+  /// A `MemoryMax` value, such as `2G`.
+  pub memory: Option<String>,
+  /// A `CPUQuota` value, such as `50%` (or `200%` for two cores).
+  pub cpu: Option<String>,
 }
 
 impl Step {
-  pub fn assemble(config: &Config, step: &Step) -> Vec<String> {
+  /// The task this step invokes, if it is a `task` step.
+  pub fn task(&self) -> Option<&str> {
+    match &self.exec {
+      Exec::Task(exec) => Some(&exec.task),
+      _ => None,
+    }
+  }
+
+  /// Prints the shell line a dry run would execute, with where it would run
+  /// and anything that changes how. A step with limits or a container also
+  /// shows the full `command` it would start. Retries are noted since they
+  /// won't be exercised.
+  fn print_dry_run(
+    &self,
+    script: &str,
+    command: &Command,
+    path: &Path,
+    deps: &[String],
+  ) {
+    println!("would run: {script}");
+    if self.limits.is_some() || self.container.is_some() {
+      println!("  as: {}", display_command(command));
+    }
+    let cwd = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    println!("  cwd: {}", cwd.display());
+    if !deps.is_empty() {
+      println!("  deps: {}", deps.join(" "));
+    }
+    if self.retries > 0 {
+      print!("  retried up to {} time(s)", self.retries);
+      if let Some(delay) = self.retry_delay {
+        print!(", {} apart", humantime::format_duration(delay));
+      }
+      println!();
+    }
+  }
+
+  /// Builds the command that runs `cmd` through a shell in `path`, inside
+  /// the step's container or else a shell with `deps`.
+  fn shell(
+    &self,
+    path: &PathBuf,
+    cmd: &str,
+    deps: &[String],
+    inherit: bool,
+    ctx: &ShellContext,
+  ) -> Command {
+    match &self.container {
+      Some(image) => container_shell(path, image, cmd, inherit, ctx),
+      None => Cmds::Single(cmd.to_string()).assemble(
+        path,
+        if deps.is_empty() {
+          None
+        } else {
+          Some(deps.iter())
+        },
+        inherit,
+        ctx,
+      ),
+    }
+  }
+
+  /// Wraps `command` in the step's resource limits and, with `--pty`, a
+  /// pseudo-terminal.
+  fn wrap(
+    &self,
+    command: Command,
+    ctx: &ShellContext,
+    opts: &RunOptions,
+  ) -> Command {
+    let mut command = match &self.limits {
+      Some(limits) => {
+        with_limits(&command, limits.memory.as_deref(), limits.cpu.as_deref())
+      }
+      None => command,
+    };
+    if opts.pty {
+      command = with_pty(&command);
+    }
+    // The wrappers inherit our environment, so clear theirs as well.
+    if ctx.env_clear && (self.limits.is_some() || opts.pty) {
+      clear_env(&mut command);
+    }
+    command
+  }
+
+  /// Expands the step (and any tasks it invokes) into shell commands. `vars`
+  /// are substituted everywhere, with task arguments taking precedence.
+  pub fn assemble(
+    config: &Config,
+    step: &Step,
+    vars: &HashMap<String, String>,
+  ) -> Result<Vec<String>, AssembleError> {
     let mut cmds = Vec::new();
-    let mut queue: VecDeque<(&Step, HashMap<String, String>)> = VecDeque::new();
-    queue.push_back((step, HashMap::new()));
+    // Each queued step carries the chain of tasks that led to it, so a task
+    // that (indirectly) invokes itself is caught instead of looping forever.
+    let mut queue: VecDeque<(&Step, HashMap<String, String>, Vec<&str>)> =
+      VecDeque::new();
+    queue.push_back((step, vars.clone(), Vec::new()));
 
-    while let Some((current, args)) = queue.pop_front() {
+    while let Some((current, args, chain)) = queue.pop_front() {
       match &current.exec {
         Exec::Run { run } => {
           for cmd in run.to_vec() {
             cmds.push(substitute_args(&cmd, &args));
           }
         }
+        Exec::Argv { argv } => {
+          cmds.push(
+            argv
+              .iter()
+              .map(|arg| escape_bash_string(&substitute_args(arg, &args)))
+              .collect::<Vec<_>>()
+              .join(" "),
+          );
+        }
         Exec::Task(exec_task) => {
-          let task = config.tasks.get(&exec_task.task).expect("task not found");
+          if chain.contains(&exec_task.task.as_str()) {
+            let mut cycle: Vec<String> =
+              chain.iter().map(|name| name.to_string()).collect();
+            cycle.push(exec_task.task.clone());
+            return Err(AssembleError::TaskCycle(cycle));
+          }
+          let task = config.tasks.get(&exec_task.task).ok_or_else(|| {
+            AssembleError::TaskNotFound(exec_task.task.clone())
+          })?;
+
+          // The task sees its caller's args, so values keep flowing
+          // through nested invocations, with its own `with` taking
+          // precedence.
+          let mut task_args = args.clone();
+          task_args.extend(exec_task.with.iter().map(|(key, value)| {
+            (key.clone(), substitute_args(&value.joined(), &args))
+          }));
 
           let missing_args: Vec<String> = task
             .args
             .iter()
-            .filter(|arg| !exec_task.with.contains_key(*arg))
+            .filter(|arg| !task_args.contains_key(*arg))
             .cloned()
             .collect();
 
           if !missing_args.is_empty() {
-            panic!(
-              "task '{}' requires arguments: {}, but only provided: {}",
-              exec_task.task,
-              missing_args.join(", "),
-              exec_task
-                .with
-                .keys()
-                .cloned()
-                .collect::<Vec<_>>()
-                .join(", ")
-            );
+            let mut provided: Vec<_> = task_args.keys().cloned().collect();
+            provided.sort();
+            return Err(AssembleError::MissingArgs {
+              task: exec_task.task.clone(),
+              missing: missing_args,
+              provided,
+            });
           }
 
-          let task_args = exec_task.with.clone();
+          let mut chain = chain.clone();
+          chain.push(&exec_task.task);
           for task_step in &task.steps {
-            queue.push_back((task_step, task_args.clone()));
+            queue.push_back((task_step, task_args.clone(), chain.clone()));
           }
         }
       }
     }
 
-    cmds
+    Ok(cmds)
+  }
+}
+
+/// Roots the nix environment for `deps`. Failing to do so only costs a
+/// later re-download, so it is reported and otherwise ignored.
+fn add_gc_root(dir: &Path, project: &str, deps: &[String]) {
+  let root = gc_root(dir, project, deps);
+  if root.exists() {
+    return;
+  }
+
+  let result =
+    fs::create_dir_all(dir).and_then(|_| nix_gc_root(deps, &root).status());
+  match result {
+    Ok(status) if status.success() => {}
+    Ok(_) => println!("warning: could not add GC root {}", root.display()),
+    Err(e) => {
+      println!("warning: could not add GC root {}: {e}", root.display())
+    }
+  }
+}
+
+/// Prints where a step runs, with which deps and which extra variables.
+fn print_step_details(path: &Path, deps: &[String], ctx: &ShellContext) {
+  println!("cwd: {}", path.display());
+  if !deps.is_empty() {
+    println!("deps: {}", deps.join(" "));
+  }
+  for (key, value) in ctx.env.iter() {
+    println!("env: {key}={value}");
+  }
+}
+
+/// Creates a fresh, empty temporary file for a phase's persisted
+/// environment. Steps source it, so it is created exclusively and only
+/// readable by us; a name someone else took first is skipped.
+fn persisted_env_file() -> std::io::Result<PathBuf> {
+  static COUNTER: AtomicUsize = AtomicUsize::new(0);
+  loop {
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!(
+      "procon-{}-{}.env",
+      std::process::id(),
+      n
+    ));
+    match fs::OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .mode(0o600)
+      .open(&path)
+    {
+      Ok(_) => return Ok(path),
+      Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+      Err(e) => return Err(e),
+    }
   }
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
 pub struct Phase {
-  steps: Vec<Step>,
+  pub steps: Vec<Step>,
+  /// Carry exported variables (such as `PATH` additions) from one shell step
+  /// to the next. After each step, `export -p` is written to a temporary
+  /// file that the next step sources before running.
+  ///
+  /// Limitations: only exported variables survive, not shell functions,
+  /// aliases, or unexported variables, and the shell's own `PWD`, `OLDPWD`,
+  /// `SHLVL` and `_` are left out. Variables that point at a nix-shell's
+  /// temporary directories go stale once that shell exits. `argv` steps with
+  /// `shell: false` neither read nor write the file.
+  #[serde(default)]
+  pub persist_env: bool,
+  /// Steps run before the phase's own. If one fails, the phase fails
+  /// without running its steps.
+  #[serde(default)]
+  pub pre: Vec<Step>,
+  /// Steps run after the phase, even when it failed, with
+  /// `PROCON_PHASE_STATUS` set to `success` or `failed`.
+  #[serde(default)]
+  pub post: Vec<Step>,
 }
 
 impl Phase {
+  /// Runs the phase for `project`, returning its status and the (1-based)
+  /// numbers of the steps that failed.
   pub fn run(
     &self,
     config: &Config,
     project: &Project,
     project_name: &str,
-    dry_run: bool,
-  ) -> bool {
-    for step in self.steps.iter() {
-      let path = if let Some(cwd) = &step.cwd {
-        project.dir.join(cwd).clean()
+    opts: &RunOptions,
+  ) -> (Status, Vec<usize>) {
+    let mut ctx = project.shell_context(config, project_name);
+    if let Err(e) = project.load_env_file(&mut ctx) {
+      println!("error: {e}");
+      return (Status::Failed, Vec::new());
+    }
+
+    self.run_with(config, project, ctx, opts)
+  }
+
+  /// Like [`Phase::run`], but with a prepared shell context.
+  pub fn run_with(
+    &self,
+    config: &Config,
+    project: &Project,
+    ctx: ShellContext,
+    opts: &RunOptions,
+  ) -> (Status, Vec<usize>) {
+    self.run_recorded(config, project, ctx, opts, None)
+  }
+
+  /// The steps the phase runs for `project`, in order, with their step
+  /// numbers: the phase's own steps count from 1, while hooks are step 0,
+  /// as their commands are recorded.
+  pub fn numbered_steps<'a>(
+    &'a self,
+    project: &'a Project,
+  ) -> Vec<(usize, &'a Step)> {
+    let hooks = |steps: &'a [Step]| steps.iter().map(|step| (0, step));
+    hooks(&project.pre)
+      .chain(hooks(&self.pre))
+      .chain(self.steps.iter().enumerate().map(|(i, step)| (i + 1, step)))
+      .chain(hooks(&self.post))
+      .chain(hooks(&project.post))
+      .collect()
+  }
+
+  /// Like [`Phase::run_with`], but if `record` is given, each command's
+  /// stdout is captured and added to it instead of being printed.
+  pub fn run_recorded(
+    &self,
+    config: &Config,
+    project: &Project,
+    mut ctx: ShellContext,
+    opts: &RunOptions,
+    record: Option<&mut Vec<CommandOutcome>>,
+  ) -> (Status, Vec<usize>) {
+    if self.persist_env && !opts.dry_run {
+      match persisted_env_file() {
+        Ok(path) => ctx.persisted_env = Some(path),
+        Err(e) => {
+          println!("error: cannot create the persisted env file: {e}");
+          return (Status::Failed, Vec::new());
+        }
+      }
+    }
+
+    if let Some(events) = &opts.events {
+      events.emit(&Event::phase_start(opts));
+    }
+    let started = Instant::now();
+
+    let result = self.run_steps(config, project, &ctx, opts, record);
+    if let Some(path) = &ctx.persisted_env {
+      let _ = fs::remove_file(path);
+    }
+
+    if let Some(events) = &opts.events {
+      events.emit(&Event::phase_end(opts, result.0, started.elapsed()));
+    }
+    result
+  }
+
+  /// Runs the `pre` hooks (the project's, then the phase's), the steps,
+  /// and, unless the run was cancelled, the `post` hooks (the phase's, then
+  /// the project's). Values captured by a step are seen by all later ones.
+  fn run_steps(
+    &self,
+    config: &Config,
+    project: &Project,
+    ctx: &ShellContext,
+    opts: &RunOptions,
+    mut record: Option<&mut Vec<CommandOutcome>>,
+  ) -> (Status, Vec<usize>) {
+    let mut captures = project.vars(&ctx.project_name);
+    let pre = project.pre.iter().chain(&self.pre);
+    let (mut status, failed) = match Self::run_hooks(
+      pre,
+      config,
+      project,
+      ctx,
+      opts,
+      &mut captures,
+      record.as_deref_mut(),
+    ) {
+      Status::Success => self.run_main_steps(
+        config,
+        project,
+        ctx,
+        opts,
+        &mut captures,
+        record.as_deref_mut(),
+      ),
+      status => (status, Vec::new()),
+    };
+    if status == Status::Cancelled {
+      return (status, failed);
+    }
+
+    let mut post_ctx = ctx.clone();
+    let outcome = if status == Status::Success {
+      "success"
+    } else {
+      "failed"
+    };
+    post_ctx
+      .env
+      .insert("PROCON_PHASE_STATUS".to_string(), outcome.to_string());
+    let post = self.post.iter().chain(&project.post);
+    match Self::run_hooks(
+      post,
+      config,
+      project,
+      &post_ctx,
+      opts,
+      &mut captures,
+      record,
+    ) {
+      Status::Success => {}
+      Status::Failed => status = Status::Failed,
+      Status::Cancelled => status = Status::Cancelled,
+    }
+    (status, failed)
+  }
+
+  /// Runs hook steps in order, stopping at the first that doesn't succeed.
+  /// Their commands are recorded under step 0.
+  fn run_hooks<'a>(
+    hooks: impl Iterator<Item = &'a Step>,
+    config: &Config,
+    project: &Project,
+    ctx: &ShellContext,
+    opts: &RunOptions,
+    captures: &mut HashMap<String, String>,
+    mut record: Option<&mut Vec<CommandOutcome>>,
+  ) -> Status {
+    for step in hooks {
+      let record = record.as_deref_mut().map(|commands| (0, commands));
+      match Self::run_step(config, project, step, ctx, opts, captures, record) {
+        Status::Success => {}
+        status => return status,
+      }
+    }
+    Status::Success
+  }
+
+  /// Runs the phase's steps in order, returning the (1-based) numbers of
+  /// those that failed. A failed step stops the phase unless
+  /// `opts.keep_going` is set.
+  fn run_main_steps(
+    &self,
+    config: &Config,
+    project: &Project,
+    ctx: &ShellContext,
+    opts: &RunOptions,
+    captures: &mut HashMap<String, String>,
+    mut record: Option<&mut Vec<CommandOutcome>>,
+  ) -> (Status, Vec<usize>) {
+    let mut failed = Vec::new();
+    for (i, step) in self.steps.iter().enumerate() {
+      let record = record.as_deref_mut().map(|commands| (i + 1, commands));
+      let step_opts = opts.for_step(i + 1);
+      match Self::run_step(
+        config, project, step, ctx, &step_opts, captures, record,
+      ) {
+        Status::Success => {}
+        Status::Failed => {
+          failed.push(i + 1);
+          if !opts.keep_going {
+            return (Status::Failed, failed);
+          }
+        }
+        Status::Cancelled => return (Status::Cancelled, failed),
+      }
+    }
+
+    if failed.is_empty() {
+      (Status::Success, failed)
+    } else {
+      (Status::Failed, failed)
+    }
+  }
+
+  /// Runs one step, recording its captured output in `captures`. With
+  /// `record`, each command is also added to it under the given step number.
+  fn run_step(
+    config: &Config,
+    project: &Project,
+    step: &Step,
+    ctx: &ShellContext,
+    opts: &RunOptions,
+    captures: &mut HashMap<String, String>,
+    mut record: Option<(usize, &mut Vec<CommandOutcome>)>,
+  ) -> Status {
+    let step_opts = RunOptions {
+      clear_on_success: opts.clear_on_success || step.clear_on_success,
+      retries: step.retries,
+      retry_on: step.retry_on.clone(),
+      retry_delay: step.retry_delay,
+      timeout: step.timeout,
+      ..opts.clone()
+    };
+    let cwd = step.cwd.as_deref().map(|cwd| expand_cwd(cwd, captures));
+    if let Some(cwd) = &cwd
+      && let Err(e) = project.check_cwd(cwd)
+    {
+      println!("error: {e}");
+      return Status::Failed;
+    }
+    let path = project.resolve_cwd(cwd.as_deref());
+    if !opts.dry_run && !path.is_dir() {
+      println!("error: cwd {} does not exist", path.display());
+      return Status::Failed;
+    }
+    let step_opts = step_opts.with_banner_var("cwd", &path.to_string_lossy());
+    let opts = &step_opts;
+
+    let mut step_ctx = ctx.clone();
+    step_ctx.env.extend(step.env.clone());
+    step_ctx.pure |= step.pure;
+    let ctx = &step_ctx;
+
+    let deps = match project.step_deps(config, step) {
+      Ok(deps) => deps,
+      Err(e) => {
+        println!("error: {e}");
+        return Status::Failed;
+      }
+    };
+
+    if ctx.flake.is_some() && !deps.is_empty() {
+      println!("error: a step can't have deps in a project using a flake");
+      return Status::Failed;
+    }
+
+    if opts.no_nix && !deps.is_empty() {
+      println!(
+        "error: step needs nix deps ({}), but --no-nix is set",
+        deps.join(" ")
+      );
+      return Status::Failed;
+    }
+
+    if let Some(flake) = &ctx.flake
+      && opts.no_nix
+    {
+      println!("error: step runs in flake {flake}, but --no-nix is set");
+      return Status::Failed;
+    }
+
+    if let Some(dir) = &opts.gc_roots
+      && !deps.is_empty()
+      && !opts.dry_run
+    {
+      add_gc_root(dir, &ctx.project_name, &deps);
+    }
+
+    if step.limits.is_some() && !opts.dry_run && !on_path("systemd-run") {
+      println!("error: step limits need systemd-run, which was not found");
+      return Status::Failed;
+    }
+
+    if step.container.is_some() && !deps.is_empty() {
+      println!("error: a step can't use both deps and a container");
+      return Status::Failed;
+    }
+
+    if opts.verbosity == Verbosity::Verbose {
+      print_step_details(&path, &deps, ctx);
+    }
+
+    match Self::guard_allows(step, &path, &deps, ctx, opts, captures) {
+      Ok(true) => {}
+      Ok(false) => return Status::Success,
+      Err(e) => {
+        println!("error: {e}");
+        return Status::Failed;
+      }
+    }
+
+    if !step.shell {
+      return Self::run_direct(step, &path, &deps, ctx, opts, captures, record);
+    }
+
+    let mut captured = Vec::new();
+    let mut cmds = match Step::assemble(config, step, captures) {
+      Ok(cmds) => cmds,
+      Err(e) => {
+        println!("error: {e}");
+        return Status::Failed;
+      }
+    };
+    if cmds.is_empty() && !deps.is_empty() && step.container.is_none() {
+      // Nothing to run, but entering the shell still fetches the deps.
+      cmds.push("true".to_string());
+    }
+    for cmd in cmds {
+      let command = step.shell(&path, &cmd, &deps, opts.inherit(), ctx);
+      let mut command = step.wrap(command, ctx, opts);
+      if opts.dry_run {
+        // A container gets the context's variables with `-e` instead.
+        let script = match &step.container {
+          Some(_) => cmd,
+          None => shell_script(&[cmd], ctx),
+        };
+        step.print_dry_run(&script, &command, &path, &deps);
+        continue;
+      }
+
+      let line = cmd;
+      let opts = &opts.for_command(&line);
+
+      if let Some((number, commands)) = record.as_mut() {
+        let (status, stdout) = match execute_captured(&mut command, opts) {
+          Ok(result) => result,
+          Err(e) => {
+            println!("error: {e}");
+            (Status::Failed, Vec::new())
+          }
+        };
+        commands.push(CommandOutcome {
+          step: *number,
+          command: line,
+          status,
+          output: String::from_utf8_lossy(&stdout).into_owned(),
+        });
+        match status {
+          Status::Success => captured.extend(stdout),
+          status => return status,
+        }
+      } else if step.capture.is_some() {
+        match execute_captured(&mut command, opts) {
+          Ok((Status::Success, stdout)) => captured.extend(stdout),
+          Ok((status, _)) => return status,
+          Err(e) => {
+            println!("error: {e}");
+            return Status::Failed;
+          }
+        }
       } else {
-        project.dir.clone()
+        match execute(&mut command, opts) {
+          Ok(Status::Success) => {}
+          Ok(status) => return status,
+          Err(e) => {
+            println!("error: {e}");
+            return Status::Failed;
+          }
+        }
+      }
+    }
+
+    if let Some(name) = &step.capture
+      && !opts.dry_run
+    {
+      let value = String::from_utf8_lossy(&captured).trim().to_string();
+      captures.insert(name.clone(), value);
+    }
+
+    Status::Success
+  }
+
+  /// Evaluates the step's `if` and `unless` predicates in its cwd and
+  /// environment, with their `{{...}}` tokens filled from `vars`, returning
+  /// whether the step should run. Dry runs only print the predicates.
+  fn guard_allows(
+    step: &Step,
+    path: &PathBuf,
+    deps: &[String],
+    ctx: &ShellContext,
+    opts: &RunOptions,
+    vars: &HashMap<String, String>,
+  ) -> std::io::Result<bool> {
+    let guards = [(&step.run_if, true), (&step.unless, false)];
+    for (predicate, wanted) in guards {
+      let Some(predicate) = predicate else {
+        continue;
       };
+      let predicate = &substitute_args(predicate, vars);
 
-      let cmds = Step::assemble(config, step);
-      for cmd in cmds {
-        let mut command = Cmds::Single(cmd).assemble(
-          &path,
-          if step.deps.is_empty() {
-            None
-          } else {
-            Some(step.deps.iter())
-          },
-          project_name,
-          &project.dir,
-        );
-
-        if dry_run {
-          println!("would run: {command:?}");
-        } else {
-          println!("{}", format!("$ {command:?}").bold());
-          match command.output() {
-            Ok(output) => {
-              if output.status.success() {
-                for _ in output.stdout {
-                  print!("\\33[2K");
-                }
-              } else {
-                println!("failed.");
-                return false;
-              }
-            }
-            Err(e) => {
-              println!("error: {e}");
-            }
-          }
+      let outcome = if wanted { "succeeds" } else { "fails" };
+      if opts.dry_run {
+        println!("would run the following only if `{predicate}` {outcome}:");
+        continue;
+      }
+
+      let mut command = step.shell(path, predicate, deps, false, ctx);
+      command.stdin(Stdio::null()).stdout(Stdio::null());
+      if command.status()?.success() != wanted {
+        if opts.verbosity > Verbosity::Quiet {
+          let result = if wanted { "failed" } else { "succeeded" };
+          println!("skipping step: `{predicate}` {result}");
         }
+        return Ok(false);
       }
     }
+    Ok(true)
+  }
+
+  /// Runs a `shell: false` step by executing its argv directly, with its
+  /// `{{...}}` tokens filled from `captures`. Its output is added to
+  /// `captures` if the step captures it.
+  fn run_direct(
+    step: &Step,
+    path: &PathBuf,
+    deps: &[String],
+    ctx: &ShellContext,
+    opts: &RunOptions,
+    captures: &mut HashMap<String, String>,
+    record: Option<(usize, &mut Vec<CommandOutcome>)>,
+  ) -> Status {
+    let argv: Vec<_> = match &step.exec {
+      Exec::Argv { argv } if !argv.is_empty() => argv
+        .iter()
+        .map(|arg| substitute_args(arg, captures))
+        .collect(),
+      Exec::Argv { .. } => {
+        println!("error: argv must not be empty");
+        return Status::Failed;
+      }
+      _ => {
+        println!("error: steps with `shell: false` must use `argv`");
+        return Status::Failed;
+      }
+    };
 
-    true
+    let command = match &step.container {
+      Some(image) => container_argv(path, image, &argv, opts.inherit(), ctx),
+      None => exec_argv(
+        path,
+        if deps.is_empty() {
+          None
+        } else {
+          Some(deps.iter())
+        },
+        &argv,
+        opts.inherit(),
+        ctx,
+      ),
+    };
+    let mut command = step.wrap(command, ctx, opts);
+    let escaped: Vec<_> =
+      argv.iter().map(|arg| escape_bash_string(arg)).collect();
+    let line = escaped.join(" ");
+    if opts.dry_run {
+      let script = match &step.container {
+        Some(_) => line,
+        None => shell_script(&[line], ctx),
+      };
+      step.print_dry_run(&script, &command, path, deps);
+      return Status::Success;
+    }
+    let opts = &opts.for_command(&line);
+    let stdout = if let Some((number, commands)) = record {
+      let (status, stdout) = match execute_captured(&mut command, opts) {
+        Ok(result) => result,
+        Err(e) => {
+          println!("error: {e}");
+          (Status::Failed, Vec::new())
+        }
+      };
+      commands.push(CommandOutcome {
+        step: number,
+        command: line,
+        status,
+        output: String::from_utf8_lossy(&stdout).into_owned(),
+      });
+      if status != Status::Success {
+        return status;
+      }
+      stdout
+    } else if step.capture.is_some() {
+      match execute_captured(&mut command, opts) {
+        Ok((Status::Success, stdout)) => stdout,
+        Ok((status, _)) => return status,
+        Err(e) => {
+          println!("error: {e}");
+          return Status::Failed;
+        }
+      }
+    } else {
+      return match execute(&mut command, opts) {
+        Ok(status) => status,
+        Err(e) => {
+          println!("error: {e}");
+          Status::Failed
+        }
+      };
+    };
+
+    if let Some(name) = &step.capture {
+      let value = String::from_utf8_lossy(&stdout).trim().to_string();
+      captures.insert(name.clone(), value);
+    }
+    Status::Success
   }
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
 pub struct Project {
+  /// Relative to the (first) config file's dir. Defaults to the directory
+  /// of `extend_from`, if that is set.
+  #[serde(default)]
   pub dir: PathBuf,
+  #[serde(default)]
   pub phases: HashMap<String, Phase>,
+  /// A project file (relative to the config file) whose phases, `deps`,
+  /// `env` and `with` values are merged under the ones declared here.
+  #[serde(default)]
+  pub extend_from: Option<PathBuf>,
+  /// Disabled projects are kept in the config but never run.
+  #[serde(default = "default_enabled")]
+  pub enabled: bool,
+  /// Phases whose failure is reported as a warning instead of stopping the
+  /// project's remaining phases.
+  #[serde(default)]
+  pub allow_failure: Vec<String>,
+  /// Default arguments for every task this project's steps invoke, lists
+  /// included as in a step's `with`. A step's own `with` takes precedence.
+  #[serde(default, deserialize_with = "joined_args")]
+  pub with: HashMap<String, String>,
+  /// Overrides the top-level `wrapper` for this project.
+  #[serde(default)]
+  pub wrapper: Option<String>,
+  /// Expands the project into one project per combination of values, named
+  /// `<name>-<value>...` in key order, with the values added to `env` and
+  /// `with`. Projects that need this one need every expansion.
+  #[serde(default)]
+  pub matrix: BTreeMap<String, Vec<String>>,
+  /// Projects whose phases must run before this project's.
+  #[serde(default)]
+  pub needs: Vec<String>,
+  /// Labels such as `db` or `web`, for selecting projects with `--tag`.
+  #[serde(default)]
+  pub tags: Vec<String>,
+  /// Variables exported to every command of every phase. A step's own `env`
+  /// wins when both set the same variable.
+  #[serde(default)]
+  pub env: HashMap<String, String>,
+  /// Nix packages (or `@set`s) added to the `deps` of every step that
+  /// doesn't run in a container.
+  #[serde(default)]
+  pub deps: Vec<String>,
+  /// A `.env` file, relative to `dir`, whose variables are exported to every
+  /// command. `env` (and a step's `env`) win over the file.
+  #[serde(default)]
+  pub env_file: Option<PathBuf>,
+  /// The shell that runs the project's commands (default: `bash`). It must
+  /// accept `-c` and POSIX quoting, as `sh` and `zsh` do.
+  #[serde(default)]
+  pub shell: Option<String>,
+  /// Where the project's nix tools come from: each step's `deps` (the
+  /// default), or a flake's dev shell.
+  #[serde(default)]
+  pub nix: Nix,
+  /// Steps run before each of the project's phases, ahead of the phase's
+  /// own `pre`.
+  #[serde(default)]
+  pub pre: Vec<Step>,
+  /// Steps run after each of the project's phases (even failed ones), after
+  /// the phase's own `post`.
+  #[serde(default)]
+  pub post: Vec<Step>,
+  /// `cwd` values, as written, that may point outside `dir`, such as `..`
+  /// for a step that clones the project. Any other `cwd` must stay inside.
+  #[serde(default)]
+  pub allow_cwd: Vec<PathBuf>,
+  /// Run nix shells with `--pure`, so host tools on `PATH` can't leak in.
+  /// Only `TERM`, `HOME` and the like survive, so every tool must be in
+  /// `deps` and every variable in `env`.
+  #[serde(default)]
+  pub pure: bool,
+  /// Lets steps use any `cwd`. Set for the project global commands run as,
+  /// never read from the config.
+  #[serde(skip)]
+  pub unconfined: bool,
+}
+
+/// How a project's commands get their nix tools.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Nix {
+  /// `nix-shell -p` with the packages in each step's `deps`.
+  #[default]
+  Packages,
+  /// `nix develop` with a flake reference such as `.#devShell`, resolved
+  /// from the step's directory. Steps can't list `deps` in this mode.
+  Flake { flake: String },
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+fn default_enabled() -> bool {
+  true
+}
+
+/// The part of a project that can live in its own file, referenced by the
+/// root config's `extend_from`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct ProjectFragment {
+  #[serde(default)]
+  pub phases: HashMap<String, Phase>,
+  #[serde(default)]
+  pub deps: Vec<String>,
+  #[serde(default)]
+  pub env: HashMap<String, String>,
+  #[serde(default, deserialize_with = "joined_args")]
+  pub with: HashMap<String, String>,
+}
+
+impl Project {
+  /// Merges a fragment under this project. Phases, `env` and `with` values
+  /// declared inline win over the fragment's, and the fragment's `deps` are
+  /// added after the inline ones.
+  pub fn extend(&mut self, fragment: ProjectFragment) {
+    for (name, phase) in fragment.phases {
+      self.phases.entry(name).or_insert(phase);
+    }
+    for dep in fragment.deps {
+      if !self.deps.contains(&dep) {
+        self.deps.push(dep);
+      }
+    }
+    for (key, value) in fragment.env {
+      self.env.entry(key).or_insert(value);
+    }
+    for (key, value) in fragment.with {
+      self.with.entry(key).or_insert(value);
+    }
+  }
+
+  /// The nix packages `step` gets: the project's `deps` (unless the step
+  /// runs in a container) and then its own, with dep sets expanded.
+  pub fn step_deps(
+    &self,
+    config: &Config,
+    step: &Step,
+  ) -> Result<Vec<String>, String> {
+    if step.container.is_some() {
+      return config.resolve_deps(&step.deps);
+    }
+    let deps: Vec<_> = self.deps.iter().chain(&step.deps).cloned().collect();
+    config.resolve_deps(&deps)
+  }
+
+  /// The values a step's `{{...}}` tokens are filled from: the project's
+  /// `with`, plus the built-in `PROJECT_NAME` and `PROJECT_DIR` unless
+  /// `with` sets them.
+  pub fn vars(&self, name: &str) -> HashMap<String, String> {
+    let mut vars = self.with.clone();
+    let dir = fs::canonicalize(&self.dir).unwrap_or_else(|_| self.dir.clone());
+    vars
+      .entry("PROJECT_NAME".into())
+      .or_insert_with(|| name.into());
+    vars
+      .entry("PROJECT_DIR".into())
+      .or_insert_with(|| dir.to_string_lossy().into_owned());
+    vars
+  }
+
+  /// Every step of the project: those of its phases and both phase and
+  /// project hooks.
+  pub fn steps(&self) -> impl Iterator<Item = &Step> {
+    self
+      .phases
+      .values()
+      .flat_map(|phase| phase.steps.iter().chain(&phase.pre).chain(&phase.post))
+      .chain(&self.pre)
+      .chain(&self.post)
+  }
+
+  /// The directory a step with the given `cwd` runs in.
+  pub fn resolve_cwd(&self, cwd: Option<&Path>) -> PathBuf {
+    match cwd {
+      Some(cwd) => self.dir.join(cwd).clean(),
+      None => self.dir.clone(),
+    }
+  }
+
+  /// Checks that a step's `cwd` resolves to somewhere inside the project
+  /// dir, unless it is in `allow_cwd`.
+  pub fn check_cwd(&self, cwd: &Path) -> Result<(), String> {
+    if self.unconfined {
+      return Ok(());
+    }
+    let path = self.resolve_cwd(Some(cwd));
+    // `{{PROJECT_DIR}}` expands to the canonical dir.
+    let canonical = fs::canonicalize(&self.dir).unwrap_or_default();
+    let escapes = !path.starts_with(self.dir.clean())
+      && (canonical.as_os_str().is_empty() || !path.starts_with(&canonical));
+    let allowed = self
+      .allow_cwd
+      .iter()
+      .any(|allowed| allowed == cwd || self.resolve_cwd(Some(allowed)) == path);
+    if escapes && !allowed {
+      return Err(format!(
+        "cwd {} is outside the project dir (add it to allow_cwd if intended)",
+        cwd.display()
+      ));
+    }
+    Ok(())
+  }
+
+  /// Adds the variables of the project's `env_file`, if it has one, to
+  /// `ctx` without overriding those already set.
+  pub fn load_env_file(&self, ctx: &mut ShellContext) -> Result<(), String> {
+    if let Some(env_file) = &self.env_file {
+      for (key, value) in dotenv::read(&self.dir.join(env_file))? {
+        ctx.env.entry(key).or_insert(value);
+      }
+    }
+    Ok(())
+  }
+
+  pub fn shell_context(&self, config: &Config, name: &str) -> ShellContext {
+    ShellContext {
+      project_name: name.to_string(),
+      project_dir: self.dir.clone(),
+      wrapper: self.wrapper.clone().or_else(|| config.wrapper.clone()),
+      env: self.env.clone().into_iter().collect(),
+      shell: self.shell.clone(),
+      pure: self.pure,
+      flake: match &self.nix {
+        Nix::Packages => None,
+        Nix::Flake { flake } => Some(flake.clone()),
+      },
+      ..Default::default()
+    }
+  }
+}
+
+/// A global command: a list of steps, optionally with its own environment.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(from = "GlobalCommandDef", into = "GlobalCommandDef")]
+pub struct GlobalCommand {
+  pub steps: Vec<Step>,
+  /// Variables exported to the steps, overriding `global_env`.
+  pub env: HashMap<String, String>,
+  /// Run the steps without inheriting procon's environment (apart from
+  /// `PATH`, `HOME`, `USER`, `TERM` and `NIX_PATH`).
+  pub env_clear: bool,
+}
+
+/// Global commands are written either as a bare list of steps or, to set an
+/// environment, as a map with `steps`.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum GlobalCommandDef {
+  Steps(Vec<Step>),
+  Full {
+    steps: Vec<Step>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    #[serde(default)]
+    env_clear: bool,
+  },
+}
+
+impl From<GlobalCommand> for GlobalCommandDef {
+  fn from(command: GlobalCommand) -> Self {
+    GlobalCommandDef::Full {
+      steps: command.steps,
+      env: command.env,
+      env_clear: command.env_clear,
+    }
+  }
+}
+
+impl From<GlobalCommandDef> for GlobalCommand {
+  fn from(def: GlobalCommandDef) -> Self {
+    match def {
+      GlobalCommandDef::Steps(steps) => GlobalCommand {
+        steps,
+        env: HashMap::new(),
+        env_clear: false,
+      },
+      GlobalCommandDef::Full {
+        steps,
+        env,
+        env_clear,
+      } => GlobalCommand {
+        steps,
+        env,
+        env_clear,
+      },
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Task {
   #[serde(default)]
   args: Vec<String>,
   steps: Vec<Step>,
 }
 
-#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+/// Where a task is invoked from.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Caller {
+  Project(String),
+  Task(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
 pub struct Config {
+  #[serde(default)]
   pub projects: HashMap<String, Project>,
   #[serde(default)]
   pub tasks: HashMap<String, Task>,
   #[serde(default)]
-  pub global: HashMap<String, Vec<Step>>,
+  pub global: HashMap<String, GlobalCommand>,
+  /// Variables exported to every global command.
+  #[serde(default)]
+  pub global_env: HashMap<String, String>,
+  /// A prefix such as `nice -n 19` or `firejail` placed in front of the
+  /// shell each step's commands run in.
+  #[serde(default)]
+  pub wrapper: Option<String>,
+  /// The default for `run --banner-format`.
+  #[serde(default)]
+  pub banner: Option<String>,
+  /// Named lists of nix packages that steps can pull in with `@name`.
+  #[serde(default)]
+  pub dep_sets: HashMap<String, Vec<String>>,
+  /// The phases `run` runs when none are given.
+  #[serde(default)]
+  pub default_phases: Vec<String>,
+  /// The projects `run` runs when neither projects nor tags are given.
+  /// Empty means every project.
+  #[serde(default)]
+  pub default_projects: Vec<String>,
+  /// The files the config was read from, `extend_from` fragments included.
+  #[serde(skip)]
+  pub files: Vec<PathBuf>,
+}
+
+impl Config {
+  /// Expands `@name` references to dep sets (which may themselves reference
+  /// other sets), dropping duplicate packages.
+  pub fn resolve_deps(&self, deps: &[String]) -> Result<Vec<String>, String> {
+    let mut resolved = Vec::new();
+    self.expand_deps(deps, &mut Vec::new(), &mut resolved)?;
+    Ok(resolved)
+  }
+
+  fn expand_deps<'a>(
+    &'a self,
+    deps: &'a [String],
+    stack: &mut Vec<&'a str>,
+    resolved: &mut Vec<String>,
+  ) -> Result<(), String> {
+    for dep in deps {
+      let Some(name) = dep.strip_prefix('@') else {
+        if !resolved.contains(dep) {
+          resolved.push(dep.clone());
+        }
+        continue;
+      };
+
+      if stack.contains(&name) {
+        return Err(format!(
+          "dep set cycle: {} -> {}",
+          stack.join(" -> "),
+          name
+        ));
+      }
+
+      let set = self
+        .dep_sets
+        .get(name)
+        .ok_or_else(|| format!("dep set '{}' not found", name))?;
+      stack.push(name);
+      self.expand_deps(set, stack, resolved)?;
+      stack.pop();
+    }
+
+    Ok(())
+  }
+
+  /// Every task invocation in the projects' steps and hooks and in other
+  /// tasks, as `(caller, task)` pairs, sorted and without duplicates.
+  pub fn task_calls(&self) -> Vec<(Caller, String)> {
+    let mut calls = Vec::new();
+    for (name, project) in &self.projects {
+      for task in project.steps().filter_map(Step::task) {
+        calls.push((Caller::Project(name.clone()), task.to_string()));
+      }
+    }
+    for (name, task) in &self.tasks {
+      for callee in task.steps.iter().filter_map(Step::task) {
+        calls.push((Caller::Task(name.clone()), callee.to_string()));
+      }
+    }
+
+    calls.sort();
+    calls.dedup();
+    calls
+  }
+
+  /// Checks that every variable set in `env` maps can be exported by a
+  /// shell.
+  pub fn check_env_names(&self) -> Result<(), String> {
+    let steps = self.located_steps().into_iter().map(|(_, step)| step);
+    let names = self
+      .projects
+      .values()
+      .flat_map(|project| project.env.keys())
+      .chain(self.global_env.keys())
+      .chain(self.global.values().flat_map(|command| command.env.keys()))
+      .chain(steps.flat_map(|step| step.env.keys()));
+
+    for name in names {
+      if !is_env_name(name) {
+        return Err(format!("invalid environment variable name '{name}'"));
+      }
+    }
+    Ok(())
+  }
+
+  /// Checks that no project step's `cwd` leaves its project dir, as
+  /// [`Project::check_cwd`] does before running one.
+  pub fn check_cwds(&self) -> Result<(), String> {
+    let mut projects: Vec<_> = self.projects.iter().collect();
+    projects.sort_by_key(|(name, _)| *name);
+    for (name, project) in projects {
+      for cwd in project.steps().filter_map(|step| step.cwd.as_deref()) {
+        project
+          .check_cwd(cwd)
+          .map_err(|e| format!("project '{name}': {e}"))?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Every step in the config, with where it is, in a stable order.
+  fn located_steps(&self) -> Vec<(String, &Step)> {
+    let mut steps = Vec::new();
+    let mut projects: Vec<_> = self.projects.iter().collect();
+    projects.sort_by_key(|(name, _)| *name);
+    for (project_name, project) in projects {
+      let mut phases: Vec<_> = project.phases.iter().collect();
+      phases.sort_by_key(|(name, _)| *name);
+      for (phase_name, phase) in phases {
+        for (i, step) in phase.steps.iter().enumerate() {
+          let location = format!(
+            "project '{project_name}', phase '{phase_name}', step {}",
+            i + 1
+          );
+          steps.push((location, step));
+        }
+        for (hook, hooks) in [("pre", &phase.pre), ("post", &phase.post)] {
+          for (i, step) in hooks.iter().enumerate() {
+            let location = format!(
+              "project '{project_name}', phase '{phase_name}', {hook} step {}",
+              i + 1
+            );
+            steps.push((location, step));
+          }
+        }
+      }
+      for (hook, hooks) in [("pre", &project.pre), ("post", &project.post)] {
+        for (i, step) in hooks.iter().enumerate() {
+          let location =
+            format!("project '{project_name}', {hook} step {}", i + 1);
+          steps.push((location, step));
+        }
+      }
+    }
+
+    let mut tasks: Vec<_> = self.tasks.iter().collect();
+    tasks.sort_by_key(|(name, _)| *name);
+    for (task_name, task) in tasks {
+      for (i, step) in task.steps.iter().enumerate() {
+        steps.push((format!("task '{task_name}', step {}", i + 1), step));
+      }
+    }
+
+    let mut global: Vec<_> = self.global.iter().collect();
+    global.sort_by_key(|(name, _)| *name);
+    for (key, command) in global {
+      for (i, step) in command.steps.iter().enumerate() {
+        steps.push((format!("global command '{key}', step {}", i + 1), step));
+      }
+    }
+    steps
+  }
+
+  /// Finds deps nix-shell would choke on. Empty deps are errors, while deps
+  /// containing whitespace (likely several packages in one item) are
+  /// returned separately as warnings.
+  pub fn dep_problems(&self) -> (Vec<String>, Vec<String>) {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    let mut projects: Vec<_> = self.projects.iter().collect();
+    projects.sort_by_key(|(name, _)| *name);
+    let project_deps = projects
+      .into_iter()
+      .map(|(name, project)| (format!("project '{name}'"), &project.deps));
+    let step_deps = self
+      .located_steps()
+      .into_iter()
+      .map(|(location, step)| (location, &step.deps));
+    for (location, deps) in project_deps.chain(step_deps) {
+      for dep in deps.iter() {
+        if dep.trim().is_empty() {
+          errors.push(format!("{location}: empty dep"));
+        } else if dep.contains(char::is_whitespace) {
+          warnings.push(format!(
+            "{location}: dep '{dep}' contains whitespace; list each package \
+             as its own item"
+          ));
+        }
+      }
+    }
+    (errors, warnings)
+  }
+
+  /// Replaces every project that has a `matrix` with its expansions, and
+  /// makes projects that need one need all of its expansions instead. Fails
+  /// if an expansion's name is already taken.
+  pub fn expand_matrices(&mut self) -> Result<(), String> {
+    let mut names: Vec<String> = self
+      .projects
+      .iter()
+      .filter(|(_, project)| !project.matrix.is_empty())
+      .map(|(name, _)| name.clone())
+      .collect();
+    names.sort();
+
+    let mut instances: HashMap<String, Vec<String>> = HashMap::new();
+    for name in names {
+      let Some(mut project) = self.projects.remove(&name) else {
+        continue;
+      };
+      let matrix = std::mem::take(&mut project.matrix);
+
+      let mut combinations = vec![(name.clone(), project)];
+      for (key, values) in matrix {
+        if values.is_empty() {
+          return Err(format!(
+            "the matrix of project '{name}' has no values for '{key}'"
+          ));
+        }
+        let mut expanded = Vec::new();
+        for (name, project) in combinations {
+          for value in values.iter() {
+            let mut project = project.clone();
+            project.env.insert(key.clone(), value.clone());
+            project.with.insert(key.clone(), value.clone());
+            expanded.push((format!("{name}-{value}"), project));
+          }
+        }
+        combinations = expanded;
+      }
+
+      for (instance, project) in combinations {
+        if self.projects.contains_key(&instance) {
+          return Err(format!(
+            "the matrix of project '{name}' expands to '{instance}', which \
+             already exists"
+          ));
+        }
+        instances
+          .entry(name.clone())
+          .or_default()
+          .push(instance.clone());
+        self.projects.insert(instance, project);
+      }
+    }
+
+    for project in self.projects.values_mut() {
+      project.needs = std::mem::take(&mut project.needs)
+        .into_iter()
+        .flat_map(|need| match instances.get(&need) {
+          Some(names) => names.clone(),
+          None => vec![need],
+        })
+        .collect();
+    }
+    Ok(())
+  }
+
+  /// Layers `other` on top of this config. Projects, tasks, and global
+  /// commands defined in `other` replace those with the same name.
+  pub fn merge(&mut self, other: Config) {
+    self.projects.extend(other.projects);
+    self.tasks.extend(other.tasks);
+    self.global.extend(other.global);
+    self.global_env.extend(other.global_env);
+    self.dep_sets.extend(other.dep_sets);
+    if other.wrapper.is_some() {
+      self.wrapper = other.wrapper;
+    }
+    if other.banner.is_some() {
+      self.banner = other.banner;
+    }
+    if !other.default_phases.is_empty() {
+      self.default_phases = other.default_phases;
+    }
+    if !other.default_projects.is_empty() {
+      self.default_projects = other.default_projects;
+    }
+    self.files.extend(other.files);
+  }
 }
@@ -0,0 +1,309 @@
+use std::{
+  collections::BTreeMap,
+  env,
+  ffi::{OsStr, OsString},
+  fs,
+  io::{self, BufRead, BufReader, PipeReader, Read, Write},
+  os::{
+    fd::{AsRawFd, RawFd},
+    unix::net::{UnixListener, UnixStream},
+  },
+  path::{Path, PathBuf},
+  sync::atomic::{AtomicBool, Ordering},
+  thread,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::run::CancellationToken;
+
+/// Marks the end of a request's output; the exit code follows it.
+const TRAILER: u8 = 0;
+
+/// How long the output relay waits for more once a request has finished,
+/// in milliseconds. Anything started in the background that still writes
+/// after that is dropped.
+const DRAIN_TIMEOUT_MS: i32 = 50;
+
+/// What a client asks the daemon to run, and where.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+  /// The command-line arguments, without the program name.
+  pub args: Vec<String>,
+  /// The client's working dir, which the request runs in.
+  pub cwd: PathBuf,
+  /// The client's environment, which the request runs with. Variables that
+  /// aren't valid UTF-8 are left out.
+  pub env: BTreeMap<String, String>,
+}
+
+impl Request {
+  /// A request to run `args` from our own working dir and environment.
+  pub fn new(args: Vec<String>) -> io::Result<Self> {
+    Ok(Self {
+      args,
+      cwd: env::current_dir()?,
+      env: env::vars_os()
+        .filter_map(|(key, value)| {
+          Some((key.into_string().ok()?, value.into_string().ok()?))
+        })
+        .collect(),
+    })
+  }
+}
+
+/// Listens on `socket` until the process is killed, handing each request to
+/// `handle`. Requests are served one at a time, in the client's working
+/// dir and environment, and while one runs our stdout and stderr (and so
+/// the output of every command it starts) are relayed to the client. If the
+/// client goes away, the token passed to `handle` is cancelled.
+pub fn serve<F>(socket: &Path, mut handle: F) -> io::Result<()>
+where
+  F: FnMut(Request, &CancellationToken) -> u8,
+{
+  if UnixStream::connect(socket).is_ok() {
+    return Err(io::Error::new(
+      io::ErrorKind::AddrInUse,
+      format!("a daemon is already listening on {}", socket.display()),
+    ));
+  }
+  if socket.exists() {
+    // Left behind by a daemon that didn't shut down cleanly.
+    fs::remove_file(socket)?;
+  }
+  if let Some(parent) = socket.parent() {
+    fs::create_dir_all(parent)?;
+  }
+
+  let listener = UnixListener::bind(socket)?;
+  println!("listening on {}", socket.display());
+  for stream in listener.incoming() {
+    let result =
+      stream.and_then(|mut stream| serve_one(&mut stream, &mut handle));
+    if let Err(e) = result {
+      eprintln!("error: {e}");
+    }
+  }
+  Ok(())
+}
+
+fn serve_one<F>(stream: &mut UnixStream, handle: &mut F) -> io::Result<()>
+where
+  F: FnMut(Request, &CancellationToken) -> u8,
+{
+  let mut line = String::new();
+  BufReader::new(&*stream).read_line(&mut line)?;
+  if line.is_empty() {
+    // Someone checking whether we're running.
+    return Ok(());
+  }
+  let mut request: Request = serde_json::from_str(&line)?;
+
+  let (output, input) = io::pipe()?;
+  let redirect = Redirect::to(input.as_raw_fd())?;
+  drop(input);
+  let client = stream.try_clone()?;
+
+  let previous_dir = env::current_dir()?;
+  if let Err(e) = env::set_current_dir(&request.cwd) {
+    writeln!(stream, "error: cannot enter {}: {e}", request.cwd.display())?;
+    return stream.write_all(&[TRAILER, 1]);
+  }
+  let previous_env = swap_env(std::mem::take(&mut request.env));
+
+  let cancel = CancellationToken::new();
+  let done = AtomicBool::new(false);
+  let code = thread::scope(|scope| {
+    let relay = scope.spawn(|| relay(output, client, &done, &cancel));
+    let code = handle(request, &cancel);
+    drop(redirect);
+    done.store(true, Ordering::SeqCst);
+    let _ = relay.join();
+    code
+  });
+
+  swap_env(previous_env);
+  env::set_current_dir(previous_dir)?;
+  // The client may be gone already, which the relay has dealt with.
+  let _ = stream.write_all(&[TRAILER, code]);
+  Ok(())
+}
+
+/// Replaces our environment with `vars`, returning the one it replaced.
+fn swap_env<K, V>(
+  vars: impl IntoIterator<Item = (K, V)>,
+) -> Vec<(OsString, OsString)>
+where
+  K: AsRef<OsStr>,
+  V: AsRef<OsStr>,
+{
+  let previous: Vec<_> = env::vars_os().collect();
+  // SAFETY: requests are served one at a time, and the environment is only
+  // swapped before the output relay starts and after it has stopped, while
+  // no other thread is running.
+  unsafe {
+    for (key, _) in &previous {
+      env::remove_var(key);
+    }
+    for (key, value) in vars {
+      env::set_var(key, value);
+    }
+  }
+  previous
+}
+
+/// Copies what is written to `output` to `client` until `output` closes
+/// or, once `done` is set, stays quiet for a moment. If the client hangs up
+/// or can't be written to, `cancel` is cancelled and `output` is still
+/// drained, so that writing to it never fails.
+fn relay(
+  mut output: PipeReader,
+  mut client: UnixStream,
+  done: &AtomicBool,
+  cancel: &CancellationToken,
+) {
+  let mut fds = [
+    libc::pollfd {
+      fd: output.as_raw_fd(),
+      events: libc::POLLIN,
+      revents: 0,
+    },
+    libc::pollfd {
+      fd: client.as_raw_fd(),
+      events: libc::POLLIN,
+      revents: 0,
+    },
+  ];
+  let mut connected = true;
+  let mut buf = [0; 8192];
+  loop {
+    // SAFETY: `fds` is an array of initialized pollfds.
+    let ready = unsafe {
+      libc::poll(
+        fds.as_mut_ptr(),
+        fds.len() as libc::nfds_t,
+        DRAIN_TIMEOUT_MS,
+      )
+    };
+    if ready < 0 {
+      if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+        continue;
+      }
+      break;
+    }
+    if ready == 0 {
+      if done.load(Ordering::SeqCst) {
+        break;
+      }
+      continue;
+    }
+
+    // The client sends nothing after its request, so the socket only
+    // becomes readable when the client hangs up.
+    if fds[1].revents != 0 {
+      connected = false;
+    }
+    if fds[0].revents != 0 {
+      match output.read(&mut buf) {
+        Ok(0) | Err(_) => break,
+        Ok(n) => {
+          if connected && client.write_all(&buf[..n]).is_err() {
+            connected = false;
+          }
+        }
+      }
+    }
+    if !connected && fds[1].fd >= 0 {
+      cancel.cancel();
+      // poll skips negative fds.
+      fds[1].fd = -1;
+    }
+  }
+}
+
+/// Sends `request` to the daemon listening on `socket` and copies its output
+/// to our stdout, returning the exit code it reports. Returns `None` if no
+/// daemon is running.
+pub fn forward(socket: &Path, request: &Request) -> io::Result<Option<u8>> {
+  let Ok(mut stream) = UnixStream::connect(socket) else {
+    return Ok(None);
+  };
+  writeln!(stream, "{}", serde_json::to_string(request)?)?;
+
+  // Output is streamed as it arrives, holding back the last two bytes until
+  // we know they aren't the trailer.
+  let mut out = io::stdout();
+  let mut held = Vec::new();
+  let mut buf = [0; 8192];
+  loop {
+    let n = stream.read(&mut buf)?;
+    if n == 0 {
+      break;
+    }
+
+    held.extend_from_slice(&buf[..n]);
+    if held.len() > 2 {
+      let rest = held.split_off(held.len() - 2);
+      out.write_all(&held)?;
+      out.flush()?;
+      held = rest;
+    }
+  }
+
+  match held[..] {
+    [TRAILER, code] => Ok(Some(code)),
+    _ => Err(io::Error::new(
+      io::ErrorKind::UnexpectedEof,
+      "the daemon closed the connection early",
+    )),
+  }
+}
+
+/// Points our stdout and stderr at another file descriptor, and our stdin at
+/// `/dev/null`, until dropped.
+struct Redirect {
+  saved: [RawFd; 3],
+}
+
+impl Redirect {
+  fn to(fd: RawFd) -> io::Result<Self> {
+    io::stdout().flush()?;
+    io::stderr().flush()?;
+    let null = fs::File::open("/dev/null")?;
+
+    let targets = [null.as_raw_fd(), fd, fd];
+    let mut saved = [-1; 3];
+    // SAFETY: dup and dup2 only operate on file descriptors, and the saved
+    // copies are restored and closed exactly once, in drop.
+    unsafe {
+      for (std_fd, target) in targets.into_iter().enumerate() {
+        let std_fd = std_fd as RawFd;
+        saved[std_fd as usize] = libc::dup(std_fd);
+        if saved[std_fd as usize] < 0 {
+          let e = io::Error::last_os_error();
+          drop(Self { saved });
+          return Err(e);
+        }
+        libc::dup2(target, std_fd);
+      }
+    }
+    Ok(Self { saved })
+  }
+}
+
+impl Drop for Redirect {
+  fn drop(&mut self) {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+
+    // SAFETY: see `Redirect::to`.
+    unsafe {
+      for (std_fd, saved) in self.saved.into_iter().enumerate() {
+        if saved >= 0 {
+          libc::dup2(saved, std_fd as RawFd);
+          libc::close(saved);
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,43 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::is_env_name;
+
+/// Reads a `.env` file of `KEY=VALUE` lines. Blank lines and lines starting
+/// with `#` are skipped, a leading `export ` is allowed, and values may be
+/// wrapped in single or double quotes.
+pub fn read(path: &Path) -> Result<BTreeMap<String, String>, String> {
+  let contents = fs::read_to_string(path)
+    .map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+  parse(&contents).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+pub fn parse(contents: &str) -> Result<BTreeMap<String, String>, String> {
+  let mut vars = BTreeMap::new();
+  for (i, line) in contents.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let Some((key, value)) = line.split_once('=') else {
+      return Err(format!("line {}: expected KEY=VALUE", i + 1));
+    };
+    let key = key.trim();
+    if !is_env_name(key) {
+      return Err(format!("line {}: invalid variable name '{key}'", i + 1));
+    }
+
+    vars.insert(key.to_string(), unquote(value.trim()).to_string());
+  }
+  Ok(vars)
+}
+
+fn unquote(value: &str) -> &str {
+  for quote in ['"', '\''] {
+    if value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote) {
+      return &value[1..value.len() - 1];
+    }
+  }
+  value
+}
@@ -0,0 +1,96 @@
+use std::{fmt, io};
+
+/// Errors returned by [`Instance`](crate::instance::Instance) commands.
+#[derive(Debug)]
+pub enum ProconError {
+  /// The config couldn't be read or parsed, or is otherwise invalid.
+  Config(String),
+  /// A command exited unsuccessfully.
+  CommandFailed(String),
+  /// A named project, global command, or similar doesn't exist.
+  NotFound(String),
+  /// The run was cancelled before it finished.
+  Cancelled,
+  Io(io::Error),
+}
+
+impl ProconError {
+  /// The process exit code for this error: 1 when a command failed (or an
+  /// I/O error occurred mid-run), 2 for config errors, 3 when something
+  /// named on the command line doesn't exist, and 130 (as for Ctrl-C) when
+  /// the run was cancelled.
+  pub fn exit_code(&self) -> u8 {
+    match self {
+      ProconError::CommandFailed(_) | ProconError::Io(_) => 1,
+      ProconError::Config(_) => 2,
+      ProconError::NotFound(_) => 3,
+      ProconError::Cancelled => 130,
+    }
+  }
+}
+
+impl fmt::Display for ProconError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ProconError::Config(msg)
+      | ProconError::CommandFailed(msg)
+      | ProconError::NotFound(msg) => f.write_str(msg),
+      ProconError::Cancelled => f.write_str("run cancelled"),
+      ProconError::Io(e) => write!(f, "{e}"),
+    }
+  }
+}
+
+impl std::error::Error for ProconError {}
+
+/// Why a step couldn't be expanded into commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssembleError {
+  TaskNotFound(String),
+  MissingArgs {
+    task: String,
+    missing: Vec<String>,
+    provided: Vec<String>,
+  },
+  /// A task invokes itself, directly or through other tasks. Holds the chain
+  /// of task names, ending with the repeated one.
+  TaskCycle(Vec<String>),
+}
+
+impl fmt::Display for AssembleError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      AssembleError::TaskNotFound(task) => {
+        write!(f, "task '{task}' not found")
+      }
+      AssembleError::MissingArgs {
+        task,
+        missing,
+        provided,
+      } => write!(
+        f,
+        "task '{}' requires arguments: {}, but only provided: {}",
+        task,
+        missing.join(", "),
+        provided.join(", ")
+      ),
+      AssembleError::TaskCycle(chain) => {
+        write!(f, "task cycle: {}", chain.join(" -> "))
+      }
+    }
+  }
+}
+
+impl std::error::Error for AssembleError {}
+
+impl From<AssembleError> for ProconError {
+  fn from(e: AssembleError) -> Self {
+    ProconError::Config(e.to_string())
+  }
+}
+
+impl From<io::Error> for ProconError {
+  fn from(e: io::Error) -> Self {
+    ProconError::Io(e)
+  }
+}
@@ -0,0 +1,109 @@
+use std::{
+  fmt::Debug,
+  io::{self, Write},
+  time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::run::{RunOptions, Status};
+
+/// Something that happened during a run, for tools that follow it.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+  PhaseStart {
+    project: &'a str,
+    phase: &'a str,
+  },
+  PhaseEnd {
+    project: &'a str,
+    phase: &'a str,
+    status: Status,
+    duration_ms: u128,
+  },
+  /// A command of a step is about to start. `step_index` is 1-based, or 0
+  /// for a hook.
+  StepStart {
+    project: &'a str,
+    phase: &'a str,
+    step_index: usize,
+    command: &'a str,
+  },
+  StepEnd {
+    project: &'a str,
+    phase: &'a str,
+    step_index: usize,
+    command: &'a str,
+    status: Status,
+    duration_ms: u128,
+  },
+}
+
+impl<'a> Event<'a> {
+  pub fn phase_start(opts: &'a RunOptions) -> Self {
+    Event::PhaseStart {
+      project: &opts.project,
+      phase: &opts.phase,
+    }
+  }
+
+  pub fn phase_end(
+    opts: &'a RunOptions,
+    status: Status,
+    duration: Duration,
+  ) -> Self {
+    Event::PhaseEnd {
+      project: &opts.project,
+      phase: &opts.phase,
+      status,
+      duration_ms: duration.as_millis(),
+    }
+  }
+
+  pub fn step_start(opts: &'a RunOptions, command: &'a str) -> Self {
+    Event::StepStart {
+      project: &opts.project,
+      phase: &opts.phase,
+      step_index: opts.step,
+      command,
+    }
+  }
+
+  pub fn step_end(
+    opts: &'a RunOptions,
+    command: &'a str,
+    status: Status,
+    duration: Duration,
+  ) -> Self {
+    Event::StepEnd {
+      project: &opts.project,
+      phase: &opts.phase,
+      step_index: opts.step,
+      command,
+      status,
+      duration_ms: duration.as_millis(),
+    }
+  }
+}
+
+/// Receives a run's events. procon's own text output doesn't go through
+/// an emitter, so one adds to it rather than replacing it.
+pub trait Emitter: Debug + Send + Sync {
+  fn emit(&self, event: &Event);
+}
+
+/// Writes each event to stderr as one line of JSON, with a `timestamp`.
+#[derive(Debug, Default)]
+pub struct JsonEmitter;
+
+impl Emitter for JsonEmitter {
+  fn emit(&self, event: &Event) {
+    let mut value = serde_json::to_value(event).unwrap_or_default();
+    if let Some(object) = value.as_object_mut() {
+      let now = humantime::format_rfc3339_millis(SystemTime::now());
+      object.insert("timestamp".into(), now.to_string().into());
+    }
+    let _ = writeln!(io::stderr().lock(), "{value}");
+  }
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeSet;
+
+use crate::config::{Caller, Config};
+
+/// The projects and tasks of a config, with the tasks each one invokes and
+/// the projects each project needs. Names referenced but not defined are
+/// still nodes, so the graph shows the dangling reference.
+struct Graph {
+  projects: BTreeSet<String>,
+  tasks: BTreeSet<String>,
+  calls: Vec<(Caller, String)>,
+  needs: Vec<(String, String)>,
+}
+
+impl Graph {
+  fn new(config: &Config) -> Self {
+    let mut needs = Vec::new();
+    for (name, project) in &config.projects {
+      for needed in &project.needs {
+        needs.push((name.clone(), needed.clone()));
+      }
+    }
+    needs.sort();
+    needs.dedup();
+    let calls = config.task_calls();
+
+    let mut projects: BTreeSet<_> = config.projects.keys().cloned().collect();
+    projects.extend(needs.iter().map(|(_, needed)| needed.clone()));
+    let mut tasks: BTreeSet<_> = config.tasks.keys().cloned().collect();
+    tasks.extend(calls.iter().map(|(_, task)| task.clone()));
+
+    Self {
+      projects,
+      tasks,
+      calls,
+      needs,
+    }
+  }
+}
+
+/// Renders the config as a Graphviz DOT graph: projects are boxes and tasks
+/// ellipses, with solid edges for task invocations and dashed ones for
+/// `needs`.
+pub fn dot(config: &Config) -> String {
+  let graph = Graph::new(config);
+  let mut out = String::from("digraph procon {\n");
+  for name in &graph.projects {
+    let id = dot_id("project", name);
+    out.push_str(&format!("  {id} [label={}, shape=box];\n", dot_quote(name)));
+  }
+  for name in &graph.tasks {
+    let id = dot_id("task", name);
+    out.push_str(&format!(
+      "  {id} [label={}, shape=ellipse];\n",
+      dot_quote(name)
+    ));
+  }
+  for (caller, task) in &graph.calls {
+    let from = match caller {
+      Caller::Project(name) => dot_id("project", name),
+      Caller::Task(name) => dot_id("task", name),
+    };
+    out.push_str(&format!("  {from} -> {};\n", dot_id("task", task)));
+  }
+  for (project, needed) in &graph.needs {
+    out.push_str(&format!(
+      "  {} -> {} [style=dashed, label=\"needs\"];\n",
+      dot_id("project", project),
+      dot_id("project", needed)
+    ));
+  }
+  out.push_str("}\n");
+  out
+}
+
+/// Renders the config as a Mermaid flowchart, with the same shapes and
+/// edges as [`dot`] (tasks are rounded).
+pub fn mermaid(config: &Config) -> String {
+  let graph = Graph::new(config);
+  // Mermaid ids can't hold arbitrary names, so nodes are numbered.
+  let project_id = |name: &str| {
+    let i = graph.projects.iter().position(|p| p == name).unwrap_or(0);
+    format!("p{i}")
+  };
+  let task_id = |name: &str| {
+    let i = graph.tasks.iter().position(|t| t == name).unwrap_or(0);
+    format!("t{i}")
+  };
+
+  let mut out = String::from("flowchart LR\n");
+  for name in &graph.projects {
+    out.push_str(&format!(
+      "  {}[\"{}\"]\n",
+      project_id(name),
+      mermaid_escape(name)
+    ));
+  }
+  for name in &graph.tasks {
+    out.push_str(&format!(
+      "  {}([\"{}\"])\n",
+      task_id(name),
+      mermaid_escape(name)
+    ));
+  }
+  for (caller, task) in &graph.calls {
+    let from = match caller {
+      Caller::Project(name) => project_id(name),
+      Caller::Task(name) => task_id(name),
+    };
+    out.push_str(&format!("  {from} --> {}\n", task_id(task)));
+  }
+  for (project, needed) in &graph.needs {
+    out.push_str(&format!(
+      "  {} -. needs .-> {}\n",
+      project_id(project),
+      project_id(needed)
+    ));
+  }
+  out
+}
+
+/// A DOT node id, prefixed with its kind since a project and a task may
+/// share a name.
+fn dot_id(kind: &str, name: &str) -> String {
+  dot_quote(&format!("{kind}:{name}"))
+}
+
+fn dot_quote(s: &str) -> String {
+  format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn mermaid_escape(s: &str) -> String {
+  s.replace('"', "#quot;")
+}
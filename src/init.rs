@@ -0,0 +1,54 @@
+use std::{
+  fs,
+  io::{self, Write},
+  path::Path,
+};
+
+use crate::error::ProconError;
+
+/// The config `procon init` writes: one project and one task, with comments
+/// pointing at the main fields.
+pub const TEMPLATE: &str = r#"# procon.yaml: run `procon run build` to build every project.
+projects:
+  # Each project runs its phases in its own dir.
+  app:
+    dir: .
+    phases:
+      build:
+        steps:
+          # `deps` are nix packages the step's commands need.
+          - deps: [hello]
+            run: hello
+      greet:
+        steps:
+          - task: say
+            with:
+              message: hello
+
+# Tasks are reusable steps. Their `{{...}}` tokens are filled from `with`.
+tasks:
+  say:
+    args: [message]
+    steps:
+      - run: echo {{message}}
+"#;
+
+/// Writes [`TEMPLATE`] to `path`, unless a file is already there and
+/// `force` isn't set.
+pub fn write(path: &Path, force: bool) -> Result<(), ProconError> {
+  let file = if force {
+    fs::File::create(path)
+  } else {
+    fs::File::create_new(path)
+  };
+  match file {
+    Ok(mut file) => Ok(file.write_all(TEMPLATE.as_bytes())?),
+    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+      Err(ProconError::Config(format!(
+        "{} already exists (pass --force to overwrite it)",
+        path.display()
+      )))
+    }
+    Err(e) => Err(e.into()),
+  }
+}
@@ -1,9 +1,29 @@
-use std::{fs, path::PathBuf};
+use std::{
+  collections::{BTreeSet, HashMap, HashSet},
+  fs, io,
+  path::{Path, PathBuf},
+  sync::{
+    Mutex,
+    atomic::{AtomicUsize, Ordering},
+  },
+  thread,
+};
 
 use colored::Colorize;
 use path_clean::PathClean;
 
-use crate::config::{Cmds, Config, Step};
+use crate::{
+  ShellContext,
+  config::{
+    Config, Nix, Phase, Project, ProjectFragment, Step, expand_cwd,
+    find_unresolved,
+  },
+  error::{AssembleError, ProconError},
+  graph,
+  manifest::write_manifest,
+  run::{Outcome, PhaseOutcome, RunOptions, Status, Summary, Verbosity},
+  state,
+};
 
 #[derive(Debug, Clone, Default)]
 pub struct Instance {
@@ -19,112 +39,1506 @@ impl Instance {
     }
   }
 
-  pub fn try_init(path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
-    let mut instance = Instance::new(path.canonicalize()?);
-    let content = fs::read_to_string(&instance.path)?;
-    let config: Config = serde_norway::from_str(&content).unwrap_or_else(|e| {
-      panic!("Failed to parse {}: {e}", instance.path.display())
-    });
-    instance.config = config;
+  pub fn try_init(path: PathBuf) -> Result<Self, ProconError> {
+    Instance::try_init_many(vec![path])
+  }
 
+  /// Loads several config files, merging them left-to-right so later files
+  /// override earlier ones. Relative project dirs resolve against the first
+  /// file's dir, wherever procon is run from.
+  pub fn try_init_many(paths: Vec<PathBuf>) -> Result<Self, ProconError> {
+    let instance = Instance::load_many(paths)?;
+    instance
+      .config
+      .check_env_names()
+      .map_err(ProconError::Config)?;
+    instance.config.check_cwds().map_err(ProconError::Config)?;
+    check_deps(&instance.config)?;
     Ok(instance)
   }
 
+  /// Like [`Instance::try_init_many`], but leaves env names, step cwds and
+  /// deps unchecked, for [`Instance::cmd_validate`] to report along with
+  /// everything else.
+  pub fn load_many(paths: Vec<PathBuf>) -> Result<Self, ProconError> {
+    let mut paths = paths.into_iter();
+    let first = paths
+      .next()
+      .ok_or_else(|| ProconError::Config("no config file given".into()))?;
+    let mut instance = Instance::new(canonicalize_config(&first)?);
+    instance.config = read_config(&instance.path)?;
+    for path in paths {
+      let config = read_config(&canonicalize_config(&path)?)?;
+      instance.config.merge(config);
+    }
+
+    let config_dir = instance.config_dir();
+    for project in instance.config.projects.values_mut() {
+      // `env_file` is relative to `dir`, so it follows along.
+      project.dir = config_dir.join(&project.dir).clean();
+    }
+
+    Ok(instance)
+  }
+
+  /// Every file the loaded config depends on: the config files, their
+  /// `extend_from` fragments and the projects' `env_file`s, sorted.
+  pub fn input_files(&self) -> Vec<PathBuf> {
+    let mut files = self.config.files.clone();
+    files.extend(self.config.projects.values().filter_map(|project| {
+      project
+        .env_file
+        .as_ref()
+        .map(|env_file| project.dir.join(env_file))
+    }));
+    files.sort();
+    files.dedup();
+    files
+  }
+
+  /// Runs each phase for the selected projects. A project whose phase fails
+  /// skips its later phases, while the others carry on; the run then
+  /// returns an error naming the failed projects. Once the run is cancelled
+  /// nothing new starts, the phases left are recorded as cancelled, and
+  /// [`ProconError::Cancelled`] is returned.
   pub fn cmd_run(
     &self,
     phase_strings: Vec<String>,
     project_filter: Option<Vec<String>>,
-    dry_run: bool,
-  ) -> Result<(), Box<dyn std::error::Error>> {
+    resume_from: Option<String>,
+    opts: &RunOptions,
+  ) -> Result<(), ProconError> {
+    let opts = &self.with_config_defaults(opts);
+    let phase_strings = if phase_strings.is_empty() {
+      self.config.default_phases.clone()
+    } else {
+      phase_strings
+    };
+    if phase_strings.is_empty() {
+      return Err(ProconError::Config(format!(
+        "no phases given and no default_phases set. Available phases: {}",
+        self.phase_names().join(", ")
+      )));
+    }
+    let project_filter = project_filter.or_else(|| {
+      let defaults = &self.config.default_projects;
+      (opts.tags.is_empty() && !defaults.is_empty()).then(|| defaults.clone())
+    });
+
+    let levels = self.need_levels()?;
+    let mut selected =
+      project_filter.map(|filter| self.matching_projects(&filter));
+    if !opts.tags.is_empty() {
+      let tagged = self.tagged_projects(&opts.tags, opts.all_tags);
+      selected = Some(match selected {
+        Some(names) => names
+          .into_iter()
+          .filter(|name| tagged.contains(name))
+          .collect(),
+        None => tagged,
+      });
+    }
+    // Projects run only because a selected one needs them.
+    let mut needed = Vec::new();
+    let project_filter = selected.map(|filter| {
+      if !opts.with_needs {
+        return filter;
+      }
+      let all = self.with_needs(filter.clone());
+      needed = all[filter.len()..].to_vec();
+      all
+    });
+    let level = |name: &str| levels.get(name).copied().unwrap_or_default();
+
+    // Run projects after the ones they need and otherwise in name order, so
+    // runs are reproducible and `resume_from` has a well-defined meaning.
+    let mut projects: Vec<_> = self.config.projects.iter().collect();
+    projects.sort_by_key(|(name, _)| (level(name), *name));
+
+    if let Some(resume_from) = resume_from {
+      let start = projects
+        .iter()
+        .position(|(name, _)| **name == resume_from)
+        .ok_or_else(|| {
+          ProconError::NotFound(format!("project '{}' not found", resume_from))
+        })?;
+      projects.drain(..start);
+    }
+
+    projects.retain(|(_, project)| project.enabled);
+
+    if let Some(seed) = opts.shuffle_seed {
+      if opts.verbosity > Verbosity::Quiet && !opts.json_plan() {
+        println!(
+          "{}",
+          format!("shuffling projects with seed {seed}").yellow()
+        );
+      }
+      shuffle(&mut projects, seed);
+      projects.sort_by_key(|(name, _)| level(name));
+    }
+
+    if opts.strict_substitution {
+      let mut problems = Vec::new();
+      for phase_name in phase_strings.iter() {
+        for (project_name, project) in projects.iter() {
+          if let Some(ref filter) = project_filter
+            && !filter.contains(project_name)
+          {
+            continue;
+          }
+
+          if let Some(phase) = project.phases.get(phase_name) {
+            let location =
+              format!("project '{project_name}', phase '{phase_name}'");
+            problems.extend(self.unresolved_in(
+              &location,
+              phase.numbered_steps(project),
+              &project.vars(project_name),
+            )?);
+          }
+        }
+      }
+      report_unresolved(problems)?;
+    }
+
     let mut ignore: Vec<String> = Vec::new();
-    for phase_string in phase_strings.into_iter() {
-      for (project_name, project) in self.config.projects.iter() {
-        if let Some(ref filter) = project_filter
-          && !filter.contains(project_name)
-        {
+    // Projects whose dependents can't run, and why.
+    let mut broken: HashMap<&str, &str> = self
+      .config
+      .projects
+      .iter()
+      .filter(|(_, project)| !project.enabled)
+      .map(|(name, _)| (name.as_str(), "is disabled"))
+      .collect();
+    let mut summary = Summary::default();
+    let mut escalated = 0;
+    let mut cancelled = false;
+    let mut plan = Vec::new();
+    // By default each phase runs for every project before the next phase
+    // starts. With `per_project`, each project runs all its phases before
+    // the next project starts.
+    let rounds: Vec<(&String, Vec<(&String, &Project)>)> = if opts.per_project {
+      projects
+        .iter()
+        .flat_map(|project| {
+          phase_strings.iter().map(|phase| (phase, vec![*project]))
+        })
+        .collect()
+    } else {
+      phase_strings
+        .iter()
+        .map(|phase| (phase, projects.clone()))
+        .collect()
+    };
+
+    for (phase_string, round) in rounds {
+      let selected = round.iter().copied().filter(|(project_name, _)| {
+        project_filter
+          .as_ref()
+          .is_none_or(|filter| filter.contains(project_name))
+          && !ignore.contains(project_name)
+      });
+      let mut jobs = Vec::new();
+      for (project_name, project) in selected {
+        if let Some(phase) = project.phases.get(phase_string) {
+          jobs.push((project_name, project, phase));
+          continue;
+        }
+        if opts.json_plan() || needed.contains(project_name) {
           continue;
         }
 
-        if ignore.contains(project_name) {
+        if opts.verbosity > Verbosity::Quiet {
+          println!(
+            "{}",
+            format!(
+              "warning: project '{}' has no phase '{}'",
+              project_name, phase_string
+            )
+            .yellow()
+          );
+        }
+        let outcome = if opts.fail_on_warning {
+          escalated += 1;
+          Outcome::Failed
+        } else {
+          Outcome::Warning
+        };
+        summary.record(project_name, phase_string, outcome, vec![]);
+      }
+
+      if opts.json_plan() {
+        for (project_name, project, phase) in jobs.iter() {
+          let mut ctx = project.shell_context(&self.config, project_name);
+          project
+            .load_env_file(&mut ctx)
+            .map_err(ProconError::Config)?;
+          plan.extend(self.plan_entries(
+            phase_string,
+            project,
+            phase.numbered_steps(project),
+            &ctx,
+          )?);
+        }
+        continue;
+      }
+
+      // Projects at the same depth in the `needs` graph can run together.
+      let batches = jobs.chunk_by(|(a, _, _), (b, _, _)| level(a) == level(b));
+      for batch in batches {
+        if cancelled {
+          // Nothing new starts once the run is cancelled.
+          for (project_name, _, _) in batch {
+            summary.record(
+              project_name,
+              phase_string,
+              Outcome::Cancelled,
+              vec![],
+            );
+          }
           continue;
         }
 
-        if let Some(phase) = project.phases.get(&phase_string)
-          && !phase.run(&self.config, project, project_name, dry_run)
+        let mut runnable = Vec::new();
+        for job @ (project_name, _, _) in batch {
+          let Some(need) = self.broken_need(project_name, &broken) else {
+            runnable.push(*job);
+            continue;
+          };
+          if opts.verbosity > Verbosity::Quiet {
+            println!(
+              "{}",
+              format!(
+                "skipping phase '{}' for '{}': it needs '{}', which {}",
+                phase_string, project_name, need, broken[need]
+              )
+              .yellow()
+            );
+          }
+          summary.record(project_name, phase_string, Outcome::Skipped, vec![]);
+          broken.insert(project_name, "was skipped");
+        }
+
+        let statuses = self.run_jobs(&runnable, phase_string, opts);
+        for ((project_name, project, _), result) in
+          runnable.iter().zip(statuses)
         {
-          ignore.push(project_name.clone());
+          let Some((status, failed_steps)) = result else {
+            summary.record(
+              project_name,
+              phase_string,
+              Outcome::Cancelled,
+              vec![],
+            );
+            cancelled = true;
+            continue;
+          };
+
+          let outcome = match status {
+            Status::Success => Outcome::Success,
+            Status::Failed => {
+              let allowed = project.allow_failure.contains(phase_string);
+              if allowed && opts.fail_on_warning {
+                escalated += 1;
+              }
+
+              if allowed && !opts.fail_on_warning {
+                if opts.verbosity > Verbosity::Quiet {
+                  println!(
+                    "{}",
+                    format!(
+                      "warning: phase '{}' failed for '{}' (allowed)",
+                      phase_string, project_name
+                    )
+                    .yellow()
+                  );
+                }
+                Outcome::Warning
+              } else {
+                if !opts.keep_going {
+                  ignore.push(project_name.to_string());
+                }
+                broken.insert(project_name, "failed");
+                Outcome::Failed
+              }
+            }
+            Status::Cancelled => {
+              cancelled = true;
+              Outcome::Cancelled
+            }
+          };
+
+          summary.record(project_name, phase_string, outcome, failed_steps);
         }
       }
     }
 
+    if opts.json_plan() {
+      return print_json(&plan);
+    }
+
+    if !opts.dry_run {
+      summary.print(opts.failed_only || opts.verbosity == Verbosity::Quiet);
+
+      if let Some(path) = &opts.manifest {
+        write_manifest(&self.config_dir().join(path), &projects, &summary)?;
+      }
+    }
+
+    if cancelled {
+      return Err(ProconError::Cancelled);
+    }
+
+    if escalated > 0 {
+      return Err(ProconError::CommandFailed(format!(
+        "{escalated} warning(s) treated as errors by --fail-on-warning"
+      )));
+    }
+
+    let failed = summary.failed_projects();
+    if !failed.is_empty() {
+      return Err(ProconError::CommandFailed(format!(
+        "failed projects: {}",
+        failed.join(", ")
+      )));
+    }
+
     Ok(())
   }
 
-  pub fn cmd_run_global(
+  /// The first project in `broken` that `name` needs, directly or through
+  /// the projects it needs.
+  fn broken_need<'a>(
+    &'a self,
+    name: &str,
+    broken: &HashMap<&str, &str>,
+  ) -> Option<&'a str> {
+    let mut seen = HashSet::new();
+    let mut stack: Vec<&str> = self
+      .config
+      .projects
+      .get(name)?
+      .needs
+      .iter()
+      .map(String::as_str)
+      .collect();
+    stack.reverse();
+    while let Some(need) = stack.pop() {
+      if broken.contains_key(need) {
+        return Some(need);
+      }
+      if seen.insert(need)
+        && let Some(project) = self.config.projects.get(need)
+      {
+        stack.extend(project.needs.iter().rev().map(String::as_str));
+      }
+    }
+    None
+  }
+
+  /// Each project's depth in the `needs` graph: 0 if it needs nothing, and
+  /// otherwise one more than the deepest project it needs.
+  fn need_levels(&self) -> Result<HashMap<&str, usize>, ProconError> {
+    let mut levels = HashMap::new();
+    let mut names: Vec<_> = self.config.projects.keys().collect();
+    names.sort();
+    for name in names {
+      self.need_level(name, &mut Vec::new(), &mut levels)?;
+    }
+    Ok(levels)
+  }
+
+  fn need_level<'a>(
+    &'a self,
+    name: &'a str,
+    stack: &mut Vec<&'a str>,
+    levels: &mut HashMap<&'a str, usize>,
+  ) -> Result<usize, ProconError> {
+    if let Some(level) = levels.get(name) {
+      return Ok(*level);
+    }
+    if let Some(start) = stack.iter().position(|n| *n == name) {
+      let mut cycle = stack[start..].to_vec();
+      cycle.push(name);
+      return Err(ProconError::Config(format!(
+        "project dependency cycle: {}",
+        cycle.join(" -> ")
+      )));
+    }
+
+    stack.push(name);
+    let mut level = 0;
+    for need in self.config.projects[name].needs.iter() {
+      if !self.config.projects.contains_key(need) {
+        return Err(ProconError::Config(format!(
+          "project '{name}' needs unknown project '{need}'"
+        )));
+      }
+      level = level.max(self.need_level(need, stack, levels)? + 1);
+    }
+    stack.pop();
+
+    levels.insert(name, level);
+    Ok(level)
+  }
+
+  /// The names of the projects matched by any of the `--projects` patterns.
+  fn matching_projects(&self, patterns: &[String]) -> Vec<String> {
+    let mut names: Vec<_> = self
+      .config
+      .projects
+      .keys()
+      .filter(|name| matches_filter(patterns, name))
+      .cloned()
+      .collect();
+    names.sort();
+    names
+  }
+
+  /// Every phase name used by any project, sorted.
+  fn phase_names(&self) -> Vec<&str> {
+    let names: BTreeSet<_> = self
+      .config
+      .projects
+      .values()
+      .flat_map(|project| project.phases.keys().map(String::as_str))
+      .collect();
+    names.into_iter().collect()
+  }
+
+  /// The names of the projects with any of `tags`, or with all of them if
+  /// `all` is set.
+  fn tagged_projects(&self, tags: &[String], all: bool) -> Vec<String> {
+    let mut names: Vec<_> = self
+      .config
+      .projects
+      .iter()
+      .filter(|(_, project)| {
+        if all {
+          tags.iter().all(|tag| project.tags.contains(tag))
+        } else {
+          tags.iter().any(|tag| project.tags.contains(tag))
+        }
+      })
+      .map(|(name, _)| name.clone())
+      .collect();
+    names.sort();
+    names
+  }
+
+  /// Adds every project that the filtered projects need, directly or not.
+  fn with_needs(&self, mut filter: Vec<String>) -> Vec<String> {
+    let mut i = 0;
+    while i < filter.len() {
+      if let Some(project) = self.config.projects.get(&filter[i]) {
+        for need in project.needs.iter() {
+          if !filter.contains(need) {
+            filter.push(need.clone());
+          }
+        }
+      }
+      i += 1;
+    }
+    filter
+  }
+
+  /// Runs one phase for each of `jobs`, up to `opts.jobs` at a time. The
+  /// statuses (with the failed step numbers) are returned in the same order,
+  /// with `None` for the projects that never started because the run was
+  /// cancelled.
+  fn run_jobs(
     &self,
-    keys: Vec<String>,
-    dry_run: bool,
-  ) -> Result<(), Box<dyn std::error::Error>> {
-    let config_dir = self
-      .path
-      .parent()
-      .unwrap_or_else(|| std::path::Path::new("."))
-      .to_path_buf();
+    jobs: &[(&String, &Project, &Phase)],
+    phase_name: &str,
+    opts: &RunOptions,
+  ) -> Vec<Option<(Status, Vec<usize>)>> {
+    let run = |(project_name, project, phase): &(&String, &Project, &Phase)| {
+      let opts = opts.for_phase(project_name, phase_name);
+      phase.run(&self.config, project, project_name, &opts)
+    };
 
-    for key in keys {
-      let steps = self
+    let threads = opts.jobs.clamp(1, jobs.len().max(1));
+    if threads == 1 {
+      let mut statuses = vec![None; jobs.len()];
+      for (job, status) in jobs.iter().zip(statuses.iter_mut()) {
+        if opts.cancel.is_cancelled() {
+          break;
+        }
+
+        let result = run(job);
+        let cancelled = result.0 == Status::Cancelled;
+        *status = Some(result);
+        if cancelled {
+          break;
+        }
+      }
+      return statuses;
+    }
+
+    let next = AtomicUsize::new(0);
+    let statuses = Mutex::new(vec![None; jobs.len()]);
+    thread::scope(|scope| {
+      for _ in 0..threads {
+        scope.spawn(|| {
+          loop {
+            let i = next.fetch_add(1, Ordering::SeqCst);
+            if i >= jobs.len() || opts.cancel.is_cancelled() {
+              break;
+            }
+
+            let status = run(&jobs[i]);
+            statuses.lock().unwrap_or_else(|e| e.into_inner())[i] =
+              Some(status);
+          }
+        });
+      }
+    });
+    statuses.into_inner().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// Prints the nix packages each step and hook of the given phases would
+  /// get, after dep sets are expanded, without running anything.
+  pub fn cmd_explain_deps(
+    &self,
+    phase_strings: &[String],
+    project_filter: Option<&[String]>,
+    global: bool,
+  ) -> Result<(), ProconError> {
+    let global_project = Project::default();
+    let mut targets = Vec::new();
+    if global {
+      for key in phase_strings {
+        let command = self.config.global.get(key).ok_or_else(|| {
+          ProconError::NotFound(format!("global command '{}' not found", key))
+        })?;
+        targets.push((
+          format!("global {key}"),
+          &global_project,
+          numbered(&command.steps),
+        ));
+      }
+    } else {
+      let mut projects: Vec<_> = self
         .config
-        .global
-        .get(&key)
-        .ok_or_else(|| format!("global command '{}' not found", key))?;
+        .projects
+        .iter()
+        .filter(|(name, _)| {
+          project_filter.is_none_or(|f| matches_filter(f, name))
+        })
+        .collect();
+      projects.sort_by_key(|(name, _)| *name);
+
+      for phase_name in phase_strings {
+        for (project_name, project) in projects.iter() {
+          if let Some(phase) = project.phases.get(phase_name) {
+            targets.push((
+              format!("{project_name} {phase_name}"),
+              *project,
+              phase.numbered_steps(project),
+            ));
+          }
+        }
+      }
+    }
 
-      for step in steps.iter() {
-        let path = if let Some(cwd) = &step.cwd {
-          config_dir.join(cwd).clean()
+    for (label, project, steps) in targets {
+      println!("{}", label.bold());
+      for (number, step) in steps {
+        let mut deps = project
+          .step_deps(&self.config, step)
+          .map_err(ProconError::Config)?;
+        deps.sort();
+        let step = step_label(number);
+        if deps.is_empty() {
+          println!("  {step}: (none)");
         } else {
-          config_dir.clone()
-        };
+          println!("  {step}: {}", deps.join(" "));
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Prints every project with its phases, plus the task and global command
+  /// names, either for people or (with `json`) as JSON.
+  pub fn cmd_list(
+    &self,
+    project_filter: Option<&[String]>,
+    json: bool,
+  ) -> Result<(), ProconError> {
+    let mut projects: Vec<_> = self
+      .config
+      .projects
+      .iter()
+      .filter(|(name, _)| {
+        project_filter.is_none_or(|f| matches_filter(f, name))
+      })
+      .map(|(name, project)| {
+        let mut phases: Vec<_> = project.phases.keys().collect();
+        phases.sort();
+        (name, project, phases)
+      })
+      .collect();
+    projects.sort_by_key(|(name, _, _)| *name);
+    let mut tasks: Vec<_> = self.config.tasks.keys().collect();
+    tasks.sort();
+    let mut global: Vec<_> = self.config.global.keys().collect();
+    global.sort();
+
+    if json {
+      let projects: Vec<_> = projects
+        .iter()
+        .map(|(name, project, phases)| {
+          serde_json::json!({
+            "name": name,
+            "enabled": project.enabled,
+            "tags": project.tags,
+            "phases": phases,
+          })
+        })
+        .collect();
+      let list = serde_json::json!({
+        "projects": projects,
+        "tasks": tasks,
+        "global": global,
+      });
+      return print_json(&list);
+    }
+
+    println!("{}", "projects:".bold());
+    for (name, project, phases) in projects.iter() {
+      let phases: Vec<_> = phases.iter().map(|p| p.as_str()).collect();
+      let disabled = if project.enabled { "" } else { " (disabled)" };
+      let tags = if project.tags.is_empty() {
+        String::new()
+      } else {
+        format!(" [{}]", project.tags.join(", "))
+      };
+      println!("  {name}{disabled}{tags}: {}", phases.join(", "));
+    }
+    if !tasks.is_empty() {
+      let tasks: Vec<_> = tasks.iter().map(|t| t.as_str()).collect();
+      println!("{} {}", "tasks:".bold(), tasks.join(", "));
+    }
+    if !global.is_empty() {
+      let global: Vec<_> = global.iter().map(|g| g.as_str()).collect();
+      println!("{} {}", "global:".bold(), global.join(", "));
+    }
+
+    Ok(())
+  }
+
+  pub fn cmd_describe(&self, project_name: &str) -> Result<(), ProconError> {
+    let project = self.project(project_name)?;
+
+    println!("{}", project_name.bold());
+    println!("  dir: {}", project.dir.display());
+    if let Some(extend_from) = &project.extend_from {
+      println!("  extend_from: {}", extend_from.display());
+    }
+    if !project.enabled {
+      println!("  enabled: false");
+    }
+    if let Some(shell) = &project.shell {
+      println!("  shell: {}", shell);
+    }
+    if let Some(env_file) = &project.env_file {
+      println!("  env_file: {}", env_file.display());
+    }
+    if let Nix::Flake { flake } = &project.nix {
+      println!("  flake: {flake}");
+    }
+    if project.pure {
+      println!("  pure: true");
+    }
+    if !project.deps.is_empty() {
+      println!("  deps: {}", project.deps.join(", "));
+    }
+    if !project.env.is_empty() {
+      let mut env: Vec<_> = project.env.iter().collect();
+      env.sort();
+      println!("  env:");
+      for (key, value) in env {
+        println!("    {}: {}", key, value);
+      }
+    }
+    if !project.with.is_empty() {
+      let mut with: Vec<_> = project.with.iter().collect();
+      with.sort();
+      println!("  with:");
+      for (key, value) in with {
+        println!("    {}: {}", key, value);
+      }
+    }
+    if !project.tags.is_empty() {
+      println!("  tags: {}", project.tags.join(", "));
+    }
+    if !project.needs.is_empty() {
+      println!("  needs: {}", project.needs.join(", "));
+    }
+    if !project.allow_failure.is_empty() {
+      println!("  allow_failure: {}", project.allow_failure.join(", "));
+    }
+    println!("  phases:");
+
+    let mut phases: Vec<_> = project.phases.iter().collect();
+    phases.sort_by_key(|(name, _)| *name);
+    for (phase_name, phase) in phases {
+      println!("    {}:", phase_name);
+      let steps = [
+        ("pre ", &phase.pre),
+        ("", &phase.steps),
+        ("post ", &phase.post),
+      ];
+      for (hook, steps) in steps {
+        for (i, step) in steps.iter().enumerate() {
+          println!("      {hook}step {}:", i + 1);
+          self.describe_step(project_name, project, step, "        ")?;
+        }
+      }
+    }
+    for (hook, steps) in [("pre", &project.pre), ("post", &project.post)] {
+      if steps.is_empty() {
+        continue;
+      }
+      println!("  {hook}:");
+      for (i, step) in steps.iter().enumerate() {
+        println!("    step {}:", i + 1);
+        self.describe_step(project_name, project, step, "      ")?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Prints a step's settings and commands for `describe`, each line
+  /// starting with `indent`.
+  fn describe_step(
+    &self,
+    project_name: &str,
+    project: &Project,
+    step: &Step,
+    indent: &str,
+  ) -> Result<(), ProconError> {
+    if let Some(cwd) = &step.cwd {
+      println!("{indent}cwd: {}", cwd.display());
+    }
+    let deps = project
+      .step_deps(&self.config, step)
+      .map_err(ProconError::Config)?;
+    if !deps.is_empty() {
+      println!("{indent}deps: {}", deps.join(", "));
+    }
+    if !step.env.is_empty() {
+      let mut env: Vec<_> = step.env.iter().collect();
+      env.sort();
+      println!("{indent}env:");
+      for (key, value) in env {
+        println!("{indent}  {}: {}", key, value);
+      }
+    }
+    if let Some(container) = &step.container {
+      println!("{indent}container: {}", container);
+    }
+    if let Some(capture) = &step.capture {
+      println!("{indent}capture: {}", capture);
+    }
+    if let Some(predicate) = &step.run_if {
+      println!("{indent}if: {}", predicate);
+    }
+    if let Some(predicate) = &step.unless {
+      println!("{indent}unless: {}", predicate);
+    }
+    if let Some(timeout) = step.timeout {
+      println!("{indent}timeout: {}", humantime::format_duration(timeout));
+    }
+    if step.retries > 0 {
+      print!("{indent}retries: {}", step.retries);
+      if !step.retry_on.is_empty() {
+        let codes: Vec<_> =
+          step.retry_on.iter().map(|code| code.to_string()).collect();
+        print!(" (on exit {})", codes.join(", "));
+      }
+      if let Some(delay) = step.retry_delay {
+        print!(", {} apart", humantime::format_duration(delay));
+      }
+      println!();
+    }
+    if let Some(limits) = &step.limits {
+      if let Some(memory) = &limits.memory {
+        println!("{indent}memory limit: {}", memory);
+      }
+      if let Some(cpu) = &limits.cpu {
+        println!("{indent}cpu limit: {}", cpu);
+      }
+    }
+    for cmd in Step::assemble(&self.config, step, &project.vars(project_name))?
+    {
+      println!("{indent}$ {}", cmd);
+    }
 
-        let cmds = Step::assemble(&self.config, step);
-        for cmd in cmds {
-          let mut command = Cmds::Single(cmd).assemble(
-            &path,
-            if step.deps.is_empty() {
-              None
-            } else {
-              Some(step.deps.iter())
-            },
-            "global",
-            &config_dir,
+    Ok(())
+  }
+
+  /// Checks the whole config without running anything: project needs,
+  /// project dirs, and for every step its tasks and arguments, its `cwd`
+  /// and its dep sets. Every problem is printed, and any makes it fail.
+  pub fn cmd_validate(&self) -> Result<(), ProconError> {
+    let mut problems = Vec::new();
+    if let Err(e) = self.need_levels() {
+      problems.push(e.to_string());
+    }
+    if let Err(e) = self.config.check_env_names() {
+      problems.push(e);
+    }
+    if let Err(e) = self.config.check_cwds() {
+      problems.push(e);
+    }
+    let (errors, warnings) = self.config.dep_problems();
+    problems.extend(errors);
+    for warning in warnings {
+      println!("{} {}", "warning:".yellow(), warning);
+    }
+
+    let mut projects: Vec<_> = self.config.projects.iter().collect();
+    projects.sort_by_key(|(name, _)| *name);
+    for (project_name, project) in projects {
+      if !project.dir.is_dir() {
+        problems.push(format!(
+          "project '{project_name}': dir {} does not exist",
+          project.dir.display()
+        ));
+      }
+
+      let mut phases: Vec<_> = project.phases.iter().collect();
+      phases.sort_by_key(|(name, _)| *name);
+      for (phase_name, phase) in phases {
+        let location =
+          format!("project '{project_name}', phase '{phase_name}'");
+        self.validate_steps(
+          &location,
+          project,
+          &phase.steps,
+          &project.vars(project_name),
+          &mut problems,
+        );
+        for (hook, hooks) in [("pre", &phase.pre), ("post", &phase.post)] {
+          self.validate_steps(
+            &format!("{location}, {hook}"),
+            project,
+            hooks,
+            &project.vars(project_name),
+            &mut problems,
           );
+        }
+      }
+      for (hook, hooks) in [("pre", &project.pre), ("post", &project.post)] {
+        self.validate_steps(
+          &format!("project '{project_name}', {hook}"),
+          project,
+          hooks,
+          &project.vars(project_name),
+          &mut problems,
+        );
+      }
+    }
 
-          if dry_run {
-            println!("would run: {command:?}");
-          } else {
-            println!("{}", format!("$ {command:?}").bold());
-            match command.output() {
-              Ok(output) => {
-                if output.status.success() {
-                  for _ in output.stdout {
-                    print!("\\33[2K");
-                  }
-                } else {
-                  println!("failed.");
-                  return Err(format!("global command '{}' failed", key).into());
-                }
-              }
-              Err(e) => {
-                println!("error: {e}");
-                return Err(
-                  format!("global command '{}' error: {}", key, e).into(),
-                );
-              }
-            }
+    let project = Project {
+      dir: self.config_dir(),
+      ..Default::default()
+    };
+    let mut global: Vec<_> = self.config.global.iter().collect();
+    global.sort_by_key(|(key, _)| *key);
+    for (key, command) in global {
+      let location = format!("global command '{key}'");
+      self.validate_steps(
+        &location,
+        &project,
+        &command.steps,
+        &HashMap::new(),
+        &mut problems,
+      );
+    }
+
+    if problems.is_empty() {
+      println!("config is valid.");
+      return Ok(());
+    }
+
+    for problem in problems.iter() {
+      println!("{} {}", "error:".red(), problem);
+    }
+    Err(ProconError::Config(format!(
+      "{} problem(s) found",
+      problems.len()
+    )))
+  }
+
+  /// Adds the problems with `steps` (run as part of `project`) to
+  /// `problems`.
+  fn validate_steps(
+    &self,
+    location: &str,
+    project: &Project,
+    steps: &[Step],
+    vars: &HashMap<String, String>,
+    problems: &mut Vec<String>,
+  ) {
+    let mut captures = vars.clone();
+    for (i, step) in steps.iter().enumerate() {
+      let location = format!("{location}, step {}", i + 1);
+      if let Err(e) = Step::assemble(&self.config, step, &captures) {
+        problems.push(format!("{location}: {e}"));
+      }
+      match project.step_deps(&self.config, step) {
+        Ok(deps) if matches!(project.nix, Nix::Flake { .. }) => {
+          if !deps.is_empty() {
+            problems
+              .push(format!("{location}: deps can't be used with a flake"));
+          }
+        }
+        Ok(_) => {}
+        Err(e) => problems.push(format!("{location}: {e}")),
+      }
+      if let Some(cwd) = &step.cwd {
+        let path = project.resolve_cwd(Some(&expand_cwd(cwd, &captures)));
+        if !path.is_dir() {
+          problems
+            .push(format!("{location}: cwd {} does not exist", path.display()));
+        }
+      }
+
+      if let Some(name) = &step.capture {
+        captures.insert(name.clone(), format!("<{name}>"));
+      }
+    }
+  }
+
+  pub fn cmd_print_resolved_path(
+    &self,
+    project_name: &str,
+    cwd: Option<&Path>,
+  ) -> Result<(), ProconError> {
+    let project = self.project(project_name)?;
+    println!("{}", absolute(project.resolve_cwd(cwd))?.display());
+
+    Ok(())
+  }
+
+  pub fn cmd_print_command(
+    &self,
+    project_name: &str,
+    phase_name: &str,
+    step_number: usize,
+  ) -> Result<(), ProconError> {
+    let project = self.project(project_name)?;
+    let phase = self.phase(project, project_name, phase_name)?;
+    let step = step_number
+      .checked_sub(1)
+      .and_then(|i| phase.steps.get(i))
+      .ok_or_else(|| {
+        ProconError::NotFound(format!(
+          "step {step_number} not found in phase '{phase_name}' of project \
+           '{project_name}'"
+        ))
+      })?;
+
+    let vars = project.vars(project_name);
+    let cwd = step.cwd.as_deref().map(|cwd| expand_cwd(cwd, &vars));
+    let path = absolute(project.resolve_cwd(cwd.as_deref()))?;
+    println!("cwd: {}", path.display());
+    let deps = project
+      .step_deps(&self.config, step)
+      .map_err(ProconError::Config)?;
+    if !deps.is_empty() {
+      println!("deps: {}", deps.join(" "));
+    }
+    // The same variables a run exports: the project's `env` over its
+    // `env_file`, then the step's own.
+    let mut ctx = project.shell_context(&self.config, project_name);
+    project
+      .load_env_file(&mut ctx)
+      .map_err(ProconError::Config)?;
+    ctx.env.extend(step.env.clone());
+    for (key, value) in ctx.env.iter() {
+      println!("env: {key}={value}");
+    }
+    for (_, _, cmd) in self
+      .assemble_steps(numbered(&phase.steps), &vars)?
+      .into_iter()
+      .filter(|(number, _, _)| *number == step_number)
+    {
+      println!("{cmd}");
+    }
+
+    Ok(())
+  }
+
+  /// Runs global commands in order. `args` fill their `{{...}}` tokens, as
+  /// a task's `with` does.
+  pub fn cmd_run_global(
+    &self,
+    keys: Vec<String>,
+    args: HashMap<String, String>,
+    opts: &RunOptions,
+  ) -> Result<(), ProconError> {
+    let opts = &self.with_config_defaults(opts);
+    let config_dir = self.config_dir();
+
+    if opts.strict_substitution {
+      let mut problems = Vec::new();
+      for key in keys.iter() {
+        if let Some(command) = self.config.global.get(key) {
+          let location = format!("global command '{key}'");
+          problems.extend(self.unresolved_in(
+            &location,
+            numbered(&command.steps),
+            &args,
+          )?);
+        }
+      }
+      report_unresolved(problems)?;
+    }
+
+    // Global commands run as phases of a synthetic project rooted at the
+    // config dir, so they share execution and reporting with projects.
+    let project = Project {
+      dir: config_dir,
+      enabled: true,
+      with: args,
+      unconfined: true,
+      ..Default::default()
+    };
+
+    let mut summary = Summary::default();
+    let mut result = Ok(());
+    let mut plan = Vec::new();
+    for key in keys {
+      if matches!(result, Err(ProconError::Cancelled)) {
+        summary.record("global", &key, Outcome::Cancelled, vec![]);
+        continue;
+      }
+
+      let command = self.config.global.get(&key).ok_or_else(|| {
+        ProconError::NotFound(format!("global command '{}' not found", key))
+      })?;
+      let phase = Phase {
+        steps: command.steps.clone(),
+        ..Default::default()
+      };
+
+      let mut ctx = project.shell_context(&self.config, "global");
+      ctx.env.extend(self.config.global_env.clone());
+      ctx.env.extend(command.env.clone());
+      ctx.env_clear = command.env_clear;
+
+      if opts.json_plan() {
+        plan.extend(self.plan_entries(
+          &key,
+          &project,
+          phase.numbered_steps(&project),
+          &ctx,
+        )?);
+        continue;
+      }
+
+      let phase_opts = opts.for_phase("global", &key);
+      let (status, failed_steps) =
+        phase.run_with(&self.config, &project, ctx, &phase_opts);
+      let outcome = match status {
+        Status::Success => Outcome::Success,
+        Status::Failed => Outcome::Failed,
+        Status::Cancelled => Outcome::Cancelled,
+      };
+
+      summary.record("global", &key, outcome, failed_steps);
+      match outcome {
+        Outcome::Failed => {
+          result = Err(ProconError::CommandFailed(format!(
+            "global command '{}' failed",
+            key
+          )));
+          if !opts.keep_going {
+            break;
           }
         }
+        Outcome::Cancelled => result = Err(ProconError::Cancelled),
+        _ => {}
+      }
+    }
+
+    if opts.json_plan() {
+      return print_json(&plan);
+    }
+
+    if !opts.dry_run {
+      summary.print(opts.failed_only || opts.verbosity == Verbosity::Quiet);
+    }
+
+    result
+  }
+
+  /// Removes the GC roots created by `run --gc-roots`, letting nix collect
+  /// the dep environments again.
+  pub fn cmd_clean(&self) -> Result<(), ProconError> {
+    let dir = state::gc_roots_dir(&state::state_dir(&self.path));
+    let mut removed = 0;
+    if dir.exists() {
+      for entry in fs::read_dir(&dir)? {
+        fs::remove_file(entry?.path())?;
+        removed += 1;
       }
+      fs::remove_dir(&dir)?;
     }
+    println!("removed {removed} GC root(s).");
 
     Ok(())
   }
+
+  fn project(&self, name: &str) -> Result<&Project, ProconError> {
+    self.config.projects.get(name).ok_or_else(|| {
+      ProconError::NotFound(format!("project '{}' not found", name))
+    })
+  }
+
+  fn phase<'a>(
+    &self,
+    project: &'a Project,
+    project_name: &str,
+    phase_name: &str,
+  ) -> Result<&'a Phase, ProconError> {
+    project.phases.get(phase_name).ok_or_else(|| {
+      ProconError::NotFound(format!(
+        "phase '{phase_name}' not found in project '{project_name}'"
+      ))
+    })
+  }
+
+  /// Fills in options the command line left unset from the config.
+  fn with_config_defaults(&self, opts: &RunOptions) -> RunOptions {
+    RunOptions {
+      banner_format: opts
+        .banner_format
+        .clone()
+        .or_else(|| self.config.banner.clone()),
+      ..opts.clone()
+    }
+  }
+
+  /// The directory containing the (first) config file.
+  pub fn config_dir(&self) -> PathBuf {
+    self
+      .path
+      .parent()
+      .unwrap_or_else(|| Path::new("."))
+      .to_path_buf()
+  }
+
+  /// The config's projects and tasks as a graph of what invokes and needs
+  /// what, in DOT (or Mermaid) syntax.
+  pub fn graph(&self, mermaid: bool) -> String {
+    if mermaid {
+      graph::mermaid(&self.config)
+    } else {
+      graph::dot(&self.config)
+    }
+  }
+
+  /// The commands a project's phase would run, in order and hooks included,
+  /// without running anything. Values captured at run time appear as
+  /// `<NAME>`.
+  pub fn plan_phase(
+    &self,
+    project_name: &str,
+    phase_name: &str,
+  ) -> Result<Vec<String>, ProconError> {
+    let project = self.project(project_name)?;
+    let phase = self.phase(project, project_name, phase_name)?;
+
+    Ok(
+      self
+        .assemble_steps(
+          phase.numbered_steps(project),
+          &project.vars(project_name),
+        )?
+        .into_iter()
+        .map(|(_, _, cmd)| cmd)
+        .collect(),
+    )
+  }
+
+  /// Runs one project's phase and returns each command with its status and
+  /// stdout, for embedding procon in other tools. Nothing but errors and
+  /// failing output is printed.
+  pub fn run_project_phase(
+    &self,
+    project_name: &str,
+    phase_name: &str,
+  ) -> Result<PhaseOutcome, ProconError> {
+    let project = self.project(project_name)?;
+    let phase = self.phase(project, project_name, phase_name)?;
+    let mut ctx = project.shell_context(&self.config, project_name);
+    project
+      .load_env_file(&mut ctx)
+      .map_err(ProconError::Config)?;
+
+    let opts = RunOptions {
+      verbosity: Verbosity::Quiet,
+      ..Default::default()
+    };
+    let mut commands = Vec::new();
+    let (status, _) = phase.run_recorded(
+      &self.config,
+      project,
+      ctx,
+      &opts,
+      Some(&mut commands),
+    );
+    Ok(PhaseOutcome { status, commands })
+  }
+
+  /// Describes each command of a phase for a JSON dry run: the project and
+  /// phase, the step number (0 for hooks), the command, and where and with
+  /// which deps and variables it would run.
+  fn plan_entries(
+    &self,
+    phase_name: &str,
+    project: &Project,
+    steps: Vec<(usize, &Step)>,
+    ctx: &ShellContext,
+  ) -> Result<Vec<serde_json::Value>, ProconError> {
+    let mut entries = Vec::new();
+    let vars = project.vars(&ctx.project_name);
+    for (number, step, cmd) in self.assemble_steps(steps, &vars)? {
+      let cwd = step.cwd.as_deref().map(|cwd| expand_cwd(cwd, &vars));
+      let deps = project
+        .step_deps(&self.config, step)
+        .map_err(ProconError::Config)?;
+      let mut env = ctx.env.clone();
+      env.extend(step.env.clone());
+      entries.push(serde_json::json!({
+        "project": ctx.project_name,
+        "phase": phase_name,
+        "step": number,
+        "command": cmd,
+        "cwd": absolute(project.resolve_cwd(cwd.as_deref()))?,
+        "deps": deps,
+        "env": env,
+      }));
+    }
+    Ok(entries)
+  }
+
+  /// Assembles every numbered step, pairing each command with its step and
+  /// the step's number.
+  fn assemble_steps<'a>(
+    &self,
+    steps: Vec<(usize, &'a Step)>,
+    vars: &HashMap<String, String>,
+  ) -> Result<Vec<(usize, &'a Step, String)>, AssembleError> {
+    let mut cmds = Vec::new();
+    // Captured values only exist at run time, so stand in placeholders for
+    // the names captured by earlier steps.
+    let mut captures = vars.clone();
+    for (number, step) in steps {
+      for cmd in Step::assemble(&self.config, step, &captures)? {
+        cmds.push((number, step, cmd));
+      }
+
+      if let Some(name) = &step.capture {
+        captures.insert(name.clone(), format!("<{name}>"));
+      }
+    }
+    Ok(cmds)
+  }
+
+  /// Assembles the numbered `steps` and describes every command that still
+  /// contains a `{{...}}` token.
+  fn unresolved_in(
+    &self,
+    location: &str,
+    steps: Vec<(usize, &Step)>,
+    vars: &HashMap<String, String>,
+  ) -> Result<Vec<String>, AssembleError> {
+    Ok(
+      self
+        .assemble_steps(steps, vars)?
+        .into_iter()
+        .filter_map(|(number, _, cmd)| {
+          let token = find_unresolved(&cmd)?;
+          let step = step_label(number);
+          Some(format!("{location}, {step}: unresolved {token} in `{cmd}`"))
+        })
+        .collect(),
+    )
+  }
+}
+
+/// How messages refer to a numbered step.
+fn step_label(number: usize) -> String {
+  match number {
+    0 => "hook".to_string(),
+    number => format!("step {number}"),
+  }
+}
+
+/// Numbers `steps` from 1, as a phase's own steps are.
+fn numbered(steps: &[Step]) -> Vec<(usize, &Step)> {
+  steps
+    .iter()
+    .enumerate()
+    .map(|(i, step)| (i + 1, step))
+    .collect()
+}
+
+fn print_json(value: &impl serde::Serialize) -> Result<(), ProconError> {
+  println!(
+    "{}",
+    serde_json::to_string_pretty(value).map_err(io::Error::from)?
+  );
+  Ok(())
+}
+
+/// Makes `path` absolute against the current dir, as commands would see it.
+fn absolute(path: PathBuf) -> Result<PathBuf, ProconError> {
+  if path.is_absolute() {
+    Ok(path)
+  } else {
+    Ok(std::env::current_dir()?.join(path).clean())
+  }
+}
+
+/// Fails on empty deps, listing whitespace warnings alongside them, and
+/// otherwise just prints the warnings.
+fn check_deps(config: &Config) -> Result<(), ProconError> {
+  let (errors, warnings) = config.dep_problems();
+  if !errors.is_empty() {
+    let problems: Vec<_> = errors.into_iter().chain(warnings).collect();
+    return Err(ProconError::Config(format!(
+      "invalid deps:\n  {}",
+      problems.join("\n  ")
+    )));
+  }
+
+  for warning in warnings {
+    println!("{} {}", "warning:".yellow(), warning);
+  }
+  Ok(())
+}
+
+fn report_unresolved(problems: Vec<String>) -> Result<(), ProconError> {
+  if problems.is_empty() {
+    return Ok(());
+  }
+
+  for problem in problems.iter() {
+    println!("{} {}", "error:".red(), problem);
+  }
+  Err(ProconError::Config(format!(
+    "{} unresolved substitution(s)",
+    problems.len()
+  )))
+}
+
+/// Whether `name` matches any of the `--projects` patterns, which are exact
+/// names or shell-style globs using `*` and `?`.
+fn matches_filter(patterns: &[String], name: &str) -> bool {
+  patterns.iter().any(|pattern| glob_match(pattern, name))
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+  let pattern: Vec<char> = pattern.chars().collect();
+  let name: Vec<char> = name.chars().collect();
+  // Where to resume if the last `*` has to swallow another character.
+  let mut star = None;
+  let (mut p, mut n) = (0, 0);
+  while n < name.len() {
+    match pattern.get(p) {
+      Some('*') => {
+        star = Some((p, n));
+        p += 1;
+      }
+      Some('?') => {
+        p += 1;
+        n += 1;
+      }
+      Some(c) if *c == name[n] => {
+        p += 1;
+        n += 1;
+      }
+      _ => match star {
+        Some((star_p, star_n)) => {
+          star = Some((star_p, star_n + 1));
+          p = star_p + 1;
+          n = star_n + 1;
+        }
+        None => return false,
+      },
+    }
+  }
+  pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Fisher-Yates shuffle driven by xorshift64*, so a seed always produces the
+/// same order.
+fn shuffle<T>(items: &mut [T], seed: u64) {
+  let mut state = seed.max(1);
+  for i in (1..items.len()).rev() {
+    state ^= state >> 12;
+    state ^= state << 25;
+    state ^= state >> 27;
+    let random = state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+    items.swap(i, (random % (i as u64 + 1)) as usize);
+  }
+}
+
+fn canonicalize_config(path: &Path) -> Result<PathBuf, ProconError> {
+  path.canonicalize().map_err(|e| {
+    ProconError::Config(format!("cannot read {}: {e}", path.display()))
+  })
+}
+
+fn read_config(path: &Path) -> Result<Config, ProconError> {
+  let content = fs::read_to_string(path).map_err(|e| {
+    ProconError::Config(format!("cannot read {}: {e}", path.display()))
+  })?;
+  let mut config: Config = serde_norway::from_str(&content).map_err(|e| {
+    ProconError::Config(format!("failed to parse {}: {e}", path.display()))
+  })?;
+
+  config.files.push(path.to_path_buf());
+  let config_dir = path.parent().unwrap_or_else(|| Path::new("."));
+  for project in config.projects.values_mut() {
+    let Some(extend_from) = project.extend_from.clone() else {
+      continue;
+    };
+
+    let fragment_path = config_dir.join(&extend_from);
+    let content = fs::read_to_string(&fragment_path).map_err(|e| {
+      ProconError::Config(format!(
+        "cannot read {}: {e}",
+        fragment_path.display()
+      ))
+    })?;
+    let fragment: ProjectFragment =
+      serde_norway::from_str(&content).map_err(|e| {
+        ProconError::Config(format!(
+          "failed to parse {}: {e}",
+          fragment_path.display()
+        ))
+      })?;
+
+    project.extend(fragment);
+    config.files.push(fragment_path);
+    if project.dir.as_os_str().is_empty()
+      && let Some(parent) = extend_from.parent()
+    {
+      project.dir = config_dir.join(parent);
+    }
+  }
+  config.expand_matrices().map_err(ProconError::Config)?;
+
+  Ok(config)
 }
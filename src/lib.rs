@@ -1,8 +1,19 @@
 pub mod config;
+pub mod daemon;
+pub mod dotenv;
+pub mod error;
+pub mod events;
+pub mod graph;
+pub mod init;
 pub mod instance;
+pub mod manifest;
 pub mod multi;
+pub mod run;
+pub mod state;
 
 use std::{
+  collections::BTreeMap,
+  ffi::OsString,
   fs,
   path::{Path, PathBuf},
   process::{Command, Stdio},
@@ -18,68 +29,447 @@ pub static SELF_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
   exe.canonicalize().unwrap_or(exe)
 });
 
-pub static NIX_SHELL_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
-  PathBuf::from_str("/nix/var/nix/profiles/default/bin/nix-shell").unwrap()
-});
+pub static NIX_SHELL_PATH: LazyLock<PathBuf> =
+  LazyLock::new(|| resolve_nix_shell(std::env::var_os("PROCON_NIX_SHELL")));
+
+/// The `nix` binary, next to nix-shell.
+pub static NIX_PATH: LazyLock<PathBuf> =
+  LazyLock::new(|| NIX_SHELL_PATH.with_file_name("nix"));
+
+/// The nix-shell binary to run: `override_path` (from `PROCON_NIX_SHELL`)
+/// if it is set and not empty, else the default profile's.
+pub fn resolve_nix_shell(override_path: Option<OsString>) -> PathBuf {
+  match override_path {
+    Some(path) if !path.is_empty() => PathBuf::from(path),
+    _ => {
+      PathBuf::from_str("/nix/var/nix/profiles/default/bin/nix-shell").unwrap()
+    }
+  }
+}
+
+/// Variables still passed to commands when their environment is cleared.
+const KEPT_ENV: [&str; 5] = ["PATH", "HOME", "USER", "TERM", "NIX_PATH"];
 
+/// Quotes `s` as a single shell word. Inside single quotes everything,
+/// including newlines and `$`, is literal, so only the quotes themselves
+/// need escaping.
 fn escape_bash_string(s: &str) -> String {
   // Escape single quotes by replacing ' with '\''
   format!("'{}'", s.replace('\'', "'\\''"))
 }
 
+/// Whether `name` can be exported by a shell: a letter or underscore, then
+/// letters, digits and underscores. Names are written into scripts as-is, so
+/// anything else is refused.
+pub fn is_env_name(name: &str) -> bool {
+  let mut chars = name.chars();
+  chars
+    .next()
+    .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Settings shared by every command run on behalf of a project (or of the
+/// global commands).
+#[derive(Debug, Clone, Default)]
+pub struct ShellContext {
+  pub project_name: String,
+  pub project_dir: PathBuf,
+  /// A prefix such as `nice -n 19` placed in front of the shell the
+  /// commands run in.
+  pub wrapper: Option<String>,
+  /// A file of exported variables that is sourced before the commands run
+  /// and rewritten after they succeed.
+  pub persisted_env: Option<PathBuf>,
+  /// Extra variables exported to every command.
+  pub env: BTreeMap<String, String>,
+  /// Don't inherit our environment, except for the variables in
+  /// [`KEPT_ENV`].
+  pub env_clear: bool,
+  /// The shell commands run in, `bash` if unset. Commands are quoted for a
+  /// POSIX shell, so it should be one (such as `sh` or `zsh`).
+  pub shell: Option<String>,
+  /// A flake whose dev shell the commands run in (`nix develop`), instead of
+  /// nix-shell with each step's deps.
+  pub flake: Option<String>,
+  /// Start nix shells from a clean environment (`--pure`, or
+  /// `--ignore-environment` for a flake). Shell steps still get the
+  /// variables in `env`, since they are part of the script.
+  pub pure: bool,
+}
+
+impl ShellContext {
+  fn shell(&self) -> &str {
+    self.shell.as_deref().unwrap_or("bash")
+  }
+
+  /// The project dir made absolute, falling back to it as given.
+  fn absolute_project_dir(&self) -> PathBuf {
+    fs::canonicalize(&self.project_dir)
+      .unwrap_or_else(|_| self.project_dir.clone())
+  }
+}
+
+/// Makes a script stop at the first failing command, including one early
+/// in a pipeline. Plain `sh` may not know `pipefail`, and failing to set it
+/// would abort.
+fn strict_mode(ctx: &ShellContext) -> &'static str {
+  if matches!(ctx.shell(), "bash" | "zsh") {
+    "set -e; set -o pipefail; "
+  } else {
+    "set -e; "
+  }
+}
+
+/// The shell line that runs `cmds` in order: the context's variables are
+/// set first, then the commands run, chained with `&&`. With a wrapper, all
+/// of that runs in a shell started behind the wrapper, so that builtins,
+/// variable assignments and pipelines work as they do without one.
+pub fn shell_script(cmds: &[String], ctx: &ShellContext) -> String {
+  // Escape project_name and project_dir for bash
+  let escaped_name = escape_bash_string(&ctx.project_name);
+  let escaped_dir =
+    escape_bash_string(&ctx.absolute_project_dir().to_string_lossy());
+
+  let mut script = String::new();
+  let escaped_env_path = ctx
+    .persisted_env
+    .as_ref()
+    .map(|path| escape_bash_string(&path.to_string_lossy()));
+  if let Some(path) = &escaped_env_path {
+    script.push_str(&format!("if [ -f {0} ]; then . {0}; fi; ", path));
+  }
+
+  // Set the environment variables once, ahead of the commands
+  script.push_str(&format!(
+    "PROJECT_NAME={} PROJECT_DIR={}; ",
+    escaped_name, escaped_dir
+  ));
+  for (key, value) in ctx.env.iter() {
+    script.push_str(&format!("export {}={}; ", key, escape_bash_string(value)));
+  }
+
+  script.push_str(&cmds.join(" && "));
+
+  if let Some(path) = &escaped_env_path {
+    // The shell sets these itself, and restoring them would leave `PWD`
+    // pointing at the previous step's dir.
+    script.push_str(&format!(
+      " && {{ export -p | grep -Ev '^(declare -x|export|typeset -x) \
+       (PWD|OLDPWD|SHLVL|_)(=|$)' || true; }} > {}",
+      path
+    ));
+  }
+
+  match &ctx.wrapper {
+    Some(wrapper) => format!(
+      "{wrapper} {} -c {}",
+      ctx.shell(),
+      escape_bash_string(&format!("{}{script}", strict_mode(ctx)))
+    ),
+    None => script,
+  }
+}
+
 pub fn nix_shell<'a, T>(
   path: &PathBuf,
   deps: Option<T>,
   cmds: &[String],
   inherit: bool,
-  project_name: &str,
-  project_dir: &Path,
+  ctx: &ShellContext,
 ) -> Command
 where
   T: Iterator<Item = &'a String>,
 {
-  // Make project_dir absolute
-  let absolute_project_dir = fs::canonicalize(project_dir)
-    .unwrap_or_else(|_| project_dir.to_path_buf());
+  let mut joined_cmds = strict_mode(ctx).to_string();
+  joined_cmds.push_str(&shell_script(cmds, ctx));
 
-  // Escape project_name and project_dir for bash
-  let escaped_name = escape_bash_string(project_name);
-  let escaped_dir = escape_bash_string(&absolute_project_dir.to_string_lossy());
-
-  // Prepend environment variables to commands
-  let env_prefix = format!(
-    "PROJECT_NAME={} PROJECT_DIR={}; ",
-    escaped_name, escaped_dir
-  );
-  let joined_cmds = cmds
-    .iter()
-    .map(|cmd| format!("{}{}", env_prefix, cmd))
-    .collect::<Vec<_>>()
-    .join("&&");
+  let mut cmd = if let Some(flake) = &ctx.flake {
+    let mut cmd = Command::new(NIX_PATH.as_path());
+    cmd.args(["develop", flake]);
+    if ctx.pure {
+      cmd.arg("--ignore-environment");
+    }
+    cmd.args(["--command", ctx.shell(), "-c"]).arg(joined_cmds);
+    cmd
+  } else if let Some(deps) = deps {
+    // nix-shell always runs `--run` with bash, so hand the commands on to
+    // any other shell.
+    if ctx.shell() != "bash" {
+      joined_cmds =
+        format!("{} -c {}", ctx.shell(), escape_bash_string(&joined_cmds));
+    }
 
-  if let Some(deps) = deps {
     let mut cmd = Command::new(NIX_SHELL_PATH.as_path());
-    if inherit {
-      cmd.stdout(Stdio::inherit());
-      cmd.stderr(Stdio::inherit());
-      cmd.stdin(Stdio::inherit());
+    if ctx.pure {
+      cmd.arg("--pure");
     }
-
-    cmd.current_dir(path);
     cmd.arg("-p").args(deps).arg("--run").arg(joined_cmds);
     cmd
   } else {
     let mut cmd = Command::new("/usr/bin/env");
-    cmd.arg("bash");
+    cmd.arg(ctx.shell()).arg("-c").arg(joined_cmds);
+    cmd
+  };
+
+  if inherit {
+    cmd.stdout(Stdio::inherit());
+    cmd.stderr(Stdio::inherit());
+    cmd.stdin(Stdio::inherit());
+  }
+
+  cmd.current_dir(path);
+  if ctx.env_clear {
+    clear_env(&mut cmd);
+  }
+  cmd
+}
+
+/// Builds a command that runs the shell command `cmd` inside a throwaway
+/// container of `image` instead of a nix shell.
+pub fn container_shell(
+  path: &Path,
+  image: &str,
+  cmd: &str,
+  inherit: bool,
+  ctx: &ShellContext,
+) -> Command {
+  let script = match &ctx.wrapper {
+    Some(wrapper) => format!("{wrapper} sh -c {}", escape_bash_string(cmd)),
+    None => cmd.to_string(),
+  };
+  container_run(
+    path,
+    image,
+    ["sh".into(), "-c".into(), script],
+    inherit,
+    ctx,
+  )
+}
 
-    if inherit {
-      cmd.stdout(Stdio::inherit());
-      cmd.stderr(Stdio::inherit());
-      cmd.stdin(Stdio::inherit());
+/// Like [`container_shell`], but executes `argv` without a shell.
+pub fn container_argv(
+  path: &Path,
+  image: &str,
+  argv: &[String],
+  inherit: bool,
+  ctx: &ShellContext,
+) -> Command {
+  let argv = ctx
+    .wrapper
+    .iter()
+    .flat_map(|wrapper| wrapper.split_whitespace())
+    .map(str::to_string)
+    .chain(argv.iter().cloned());
+  container_run(path, image, argv, inherit, ctx)
+}
+
+/// Runs `args` with `podman run` (or `docker run` if podman isn't
+/// installed). The working dir is mounted at the same path, and
+/// `PROJECT_NAME`, `PROJECT_DIR` and the context's env are passed with `-e`.
+fn container_run(
+  path: &Path,
+  image: &str,
+  args: impl IntoIterator<Item = String>,
+  inherit: bool,
+  ctx: &ShellContext,
+) -> Command {
+  let runtime = if on_path("podman") {
+    "podman"
+  } else {
+    "docker"
+  };
+  let dir = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+  let dir = dir.to_string_lossy();
+
+  let mut cmd = Command::new(runtime);
+  cmd.args(["run", "--rm"]);
+  if inherit {
+    cmd.arg("-i");
+    cmd.stdout(Stdio::inherit());
+    cmd.stderr(Stdio::inherit());
+    cmd.stdin(Stdio::inherit());
+  }
+  cmd.arg("-v").arg(format!("{dir}:{dir}"));
+  cmd.arg("-w").arg(&*dir);
+
+  cmd
+    .arg("-e")
+    .arg(format!("PROJECT_NAME={}", ctx.project_name));
+  cmd.arg("-e").arg(format!(
+    "PROJECT_DIR={}",
+    ctx.absolute_project_dir().display()
+  ));
+  for (key, value) in ctx.env.iter() {
+    cmd.arg("-e").arg(format!("{key}={value}"));
+  }
+
+  cmd.arg(image).args(args);
+  cmd
+}
+
+/// Builds a command that realises `deps` and registers the resulting shell
+/// environment as an indirect GC root at `root`, so `nix-collect-garbage`
+/// keeps it until the root is removed.
+pub fn nix_gc_root(deps: &[String], root: &Path) -> Command {
+  let mut cmd = Command::new(NIX_SHELL_PATH.as_path());
+  cmd
+    .arg("-p")
+    .args(deps)
+    .arg("--add-root")
+    .arg(root)
+    .arg("--indirect")
+    .args(["--run", "true"]);
+  cmd.stdout(Stdio::null());
+  cmd
+}
+
+/// Builds a command that executes `argv` directly instead of through a
+/// shell. `PROJECT_NAME` and `PROJECT_DIR` are set on the process
+/// environment, and a wrapper is split on whitespace into leading arguments.
+/// If there are deps, nix-shell still needs a `--run` string, so each
+/// argument is individually escaped into it. With a flake, `argv` is handed
+/// to `nix develop --command` as-is.
+pub fn exec_argv<'a, T>(
+  path: &PathBuf,
+  deps: Option<T>,
+  argv: &[String],
+  inherit: bool,
+  ctx: &ShellContext,
+) -> Command
+where
+  T: Iterator<Item = &'a String>,
+{
+  let argv: Vec<String> = ctx
+    .wrapper
+    .iter()
+    .flat_map(|wrapper| wrapper.split_whitespace())
+    .map(str::to_string)
+    .chain(argv.iter().cloned())
+    .collect();
+
+  let mut cmd = if let Some(flake) = &ctx.flake {
+    let mut cmd = Command::new(NIX_PATH.as_path());
+    cmd.args(["develop", flake]);
+    if ctx.pure {
+      cmd.arg("--ignore-environment");
     }
+    cmd.arg("--command").args(&argv);
+    cmd
+  } else if let Some(deps) = deps {
+    let escaped_argv = argv
+      .iter()
+      .map(|arg| escape_bash_string(arg))
+      .collect::<Vec<_>>()
+      .join(" ");
 
-    cmd.current_dir(path);
-    cmd.arg("-c").arg(joined_cmds);
+    let mut cmd = Command::new(NIX_SHELL_PATH.as_path());
+    if ctx.pure {
+      cmd.arg("--pure");
+    }
+    cmd.arg("-p").args(deps).arg("--run").arg(escaped_argv);
+    cmd
+  } else {
+    let mut cmd = Command::new(&argv[0]);
+    cmd.args(&argv[1..]);
     cmd
+  };
+
+  if inherit {
+    cmd.stdout(Stdio::inherit());
+    cmd.stderr(Stdio::inherit());
+    cmd.stdin(Stdio::inherit());
+  }
+
+  cmd.current_dir(path);
+  cmd.env("PROJECT_NAME", &ctx.project_name);
+  cmd.env("PROJECT_DIR", ctx.absolute_project_dir());
+  cmd.envs(ctx.env.iter());
+  if ctx.env_clear {
+    clear_env(&mut cmd);
+  }
+  cmd
+}
+
+/// Stops `command` from inheriting our environment. Variables set on it
+/// explicitly, and those in [`KEPT_ENV`], are still passed.
+pub fn clear_env(command: &mut Command) {
+  let explicit: Vec<_> = command
+    .get_envs()
+    .filter_map(|(key, value)| Some((key.to_owned(), value?.to_owned())))
+    .collect();
+
+  command.env_clear();
+  for key in KEPT_ENV {
+    if let Some(value) = std::env::var_os(key) {
+      command.env(key, value);
+    }
+  }
+  command.envs(explicit);
+}
+
+/// `command`'s program and arguments as one shell line.
+pub fn display_command(command: &Command) -> String {
+  std::iter::once(command.get_program())
+    .chain(command.get_args())
+    .map(|arg| escape_bash_string(&arg.to_string_lossy()))
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+/// Rebuilds `command` to run under util-linux `script`, which gives the
+/// child a pseudo-terminal so tools keep their colors and progress output
+/// even when we pipe it. The terminal merges stderr into stdout and ends
+/// lines with `\r\n`.
+pub fn with_pty(command: &Command) -> Command {
+  let mut cmd = Command::new("script");
+  cmd
+    .arg("-qefc")
+    .arg(display_command(command))
+    .arg("/dev/null");
+  copy_settings(command, &mut cmd);
+  cmd
+}
+
+/// Rebuilds `command` to run inside a transient systemd scope with the given
+/// `MemoryMax` and `CPUQuota` values.
+pub fn with_limits(
+  command: &Command,
+  memory: Option<&str>,
+  cpu: Option<&str>,
+) -> Command {
+  let mut cmd = Command::new("systemd-run");
+  cmd.args(["--user", "--scope", "--quiet"]);
+  if let Some(memory) = memory {
+    cmd.arg("-p").arg(format!("MemoryMax={memory}"));
+  }
+  if let Some(cpu) = cpu {
+    cmd.arg("-p").arg(format!("CPUQuota={cpu}"));
   }
+  cmd
+    .arg("--")
+    .arg(command.get_program())
+    .args(command.get_args());
+  copy_settings(command, &mut cmd);
+  cmd
+}
+
+/// Copies the working dir and environment of `from` onto `to`.
+fn copy_settings(from: &Command, to: &mut Command) {
+  if let Some(dir) = from.get_current_dir() {
+    to.current_dir(dir);
+  }
+  for (key, value) in from.get_envs() {
+    match value {
+      Some(value) => to.env(key, value),
+      None => to.env_remove(key),
+    };
+  }
+}
+
+/// Whether `program` is an executable file in one of the `PATH` dirs.
+pub fn on_path(program: &str) -> bool {
+  std::env::var_os("PATH").is_some_and(|paths| {
+    std::env::split_paths(&paths).any(|dir| dir.join(program).is_file())
+  })
 }
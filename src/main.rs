@@ -1,14 +1,37 @@
-use std::path::PathBuf;
+use std::{
+  path::{Path, PathBuf},
+  process::ExitCode,
+  sync::Arc,
+  time::{SystemTime, UNIX_EPOCH},
+};
 
-use clap::{Parser, Subcommand};
-use procon::instance::Instance;
+use clap::{Parser, Subcommand, ValueEnum};
+use colored::Colorize;
+use procon::{
+  daemon,
+  error::ProconError,
+  events::JsonEmitter,
+  init,
+  instance::Instance,
+  run::{self, CancellationToken, CommandLog, RunOptions, Verbosity},
+  state::{self, ConfigHash},
+};
 
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Cli {
-  /// Specify an alternate config file (default: procon.yaml)
+  /// Specify an alternate config file (default: procon.yaml). Can be given
+  /// multiple times; later files override earlier ones.
   #[arg(short, long)]
-  file: Option<PathBuf>,
+  file: Vec<PathBuf>,
+
+  /// Also print each step's resolved deps, working dir and environment
+  #[arg(short, long, global = true, conflicts_with = "quiet")]
+  verbose: bool,
+
+  /// Only print errors and the commands' own output
+  #[arg(short, long, global = true)]
+  quiet: bool,
 
   #[command(subcommand)]
   command: Commands,
@@ -17,44 +40,601 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
   Debug,
+  /// Write a commented example procon.yaml (or the file given with -f).
+  Init {
+    /// Overwrite the file if it already exists
+    #[arg(long)]
+    force: bool,
+  },
+  /// List projects and their phases, tasks, and global commands.
+  List {
+    /// Only list these projects
+    #[arg(short, long)]
+    projects: Vec<String>,
+
+    /// Print JSON instead of text
+    #[arg(long)]
+    json: bool,
+  },
+  /// Show a project's phases and the commands they would run.
+  Describe {
+    /// Name of the project to describe
+    project: String,
+  },
+  /// Check the whole config (tasks, arguments, dirs, deps) without running
+  /// anything, listing every problem found.
+  Validate,
+  /// Print how projects and tasks depend on each other as a graph, for
+  /// `dot -Tpng` or a Mermaid renderer.
+  Graph {
+    /// The graph syntax to print
+    #[arg(long, value_enum, default_value_t)]
+    format: GraphFormat,
+  },
+  /// Print the absolute directory a project's step would run in.
+  PrintResolvedPath {
+    /// Name of the project
+    project: String,
+
+    /// The step's `cwd`, relative to the project dir
+    cwd: Option<PathBuf>,
+  },
+  /// Keep the config loaded and serve `run` requests from other procon
+  /// invocations over a socket in `.procon`, until interrupted. Only
+  /// invocations with the same `-f` files are served, and the config is
+  /// reloaded before a run whenever one of its files has changed.
+  Daemon,
+  /// Remove the GC roots created by `run --gc-roots`.
+  Clean,
+  /// Print the fully resolved command(s) of one step, with its cwd and deps.
+  PrintCommand {
+    /// Name of the project
+    project: String,
+
+    /// Name of the phase
+    phase: String,
+
+    /// The step's number, as shown by `describe` (starting at 1)
+    step: usize,
+  },
   Run {
-    /// Phase(s) to run (or global command(s) if --global is used)
+    /// Phase(s) to run (or global command(s) if --global is used).
+    /// Defaults to the config's `default_phases`.
     phases: Vec<String>,
 
-    /// Project name(s) to filter (if not specified, runs on all projects)
+    /// Project name(s) to filter, which may be globs such as `svc-*` (if
+    /// not specified, runs on all projects). The projects they need are
+    /// only added with --with-needs.
     #[arg(short, long)]
     projects: Vec<String>,
 
+    /// Only run projects with this tag. Can be given multiple times to
+    /// select projects with any of the tags.
+    #[arg(short, long)]
+    tag: Vec<String>,
+
+    /// Select only projects that have every `--tag`
+    #[arg(long, requires = "tag")]
+    all_tags: bool,
+
+    /// Also run the projects that the selected ones need, directly or not.
+    /// Those without the phase are skipped without a warning.
+    #[arg(long)]
+    with_needs: bool,
+
     /// Run global commands instead of project phases
     #[arg(short = 'g', long)]
     global: bool,
 
+    /// Set `{{KEY}}` in the global commands' steps, like a task's `with`
+    #[arg(long = "arg", value_name = "KEY=VALUE", value_parser = parse_arg, requires = "global")]
+    args: Vec<(String, String)>,
+
     /// Dry run. Prints out commands that procon will run instead of running
     /// them.
     #[arg(short = 'n', long)]
     dry_run: bool,
+
+    /// How a dry run prints its plan
+    #[arg(long, value_enum, default_value_t, requires = "dry_run")]
+    format: Format,
+
+    /// Skip every project that sorts before this one
+    #[arg(long)]
+    resume_from: Option<String>,
+
+    /// Only print a command's output if it fails.
+    #[arg(long)]
+    quiet_on_success: bool,
+
+    /// Don't print the `$ command` line before each command.
+    #[arg(long)]
+    no_banner: bool,
+
+    /// Only list failed phases in the end-of-run summary.
+    #[arg(long)]
+    filter_failed_only: bool,
+
+    /// Fail before running anything if a command still contains an
+    /// unresolved `{{...}}` substitution.
+    #[arg(long)]
+    strict_substitution: bool,
+
+    /// On a terminal, erase each command's output once it succeeds.
+    #[arg(long)]
+    clear_on_success: bool,
+
+    /// Write a JSON manifest of each project's status, revision, and
+    /// artifact path (relative to the config dir)
+    #[arg(long, num_args = 0..=1, default_missing_value = "artifacts/manifest.json")]
+    manifest: Option<PathBuf>,
+
+    /// Hide command output unless a command fails, then show only its last
+    /// N lines.
+    #[arg(long, value_name = "N")]
+    tail_on_failure: Option<usize>,
+
+    /// Give commands a pseudo-terminal so colored and progress output is
+    /// kept even when it is captured or buffered.
+    #[arg(long)]
+    pty: bool,
+
+    /// Run projects in a shuffled order to surface hidden ordering
+    /// assumptions. Pass the printed seed to reproduce an order.
+    #[arg(long, value_name = "SEED", num_args = 0..=1)]
+    randomize_order: Option<Option<u64>>,
+
+    /// How many projects may run a phase at once, or `auto` for one per
+    /// CPU. Above 1, each command's output is printed when it finishes.
+    #[arg(short, long, value_name = "N", default_value = "1", value_parser = parse_jobs)]
+    jobs: usize,
+
+    /// Print the nix packages each step and hook would get, then exit
+    /// without running anything
+    #[arg(long)]
+    explain_deps: bool,
+
+    /// Format of the line printed before each command, using `{command}`,
+    /// `{project}`, `{phase}` and `{cwd}` (default: `$ {command}`)
+    #[arg(long, value_name = "FORMAT")]
+    banner_format: Option<String>,
+
+    /// Register each step's nix deps as GC roots under `.procon/gcroots`,
+    /// so garbage collection doesn't force re-downloads. Remove them with
+    /// `clean`.
+    #[arg(long)]
+    gc_roots: bool,
+
+    /// Treat allowed failures and projects without a requested phase as
+    /// errors, for runs that must be clean
+    #[arg(long)]
+    fail_on_warning: bool,
+
+    /// Do nothing unless the config files, their fragments or the projects'
+    /// env files changed since the last completed run with the same phases,
+    /// projects, tags and args, which keeps frequent cron runs cheap.
+    #[arg(long)]
+    only_if_config_changed: bool,
+
+    /// Keep running a project's remaining steps and phases after a step
+    /// fails (like `make -k`).
+    #[arg(short, long)]
+    keep_going: bool,
+
+    /// Run every given phase for one project before starting the next
+    /// project. By default each phase runs for all projects before the next
+    /// phase starts.
+    #[arg(long)]
+    per_project: bool,
+
+    /// Don't use nix: steps without deps run as usual, and steps with deps
+    /// fail instead of calling nix-shell. Set `PROCON_NIX_SHELL` to use a
+    /// nix-shell outside the default profile.
+    #[arg(long)]
+    no_nix: bool,
+
+    /// Append every command run, with its project, phase, step, outcome and
+    /// output, to this file (relative to the config dir). Output is copied
+    /// through procon, so commands see a pipe instead of the terminal.
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// With `json`, also report each phase and command starting and ending
+    /// as one JSON object per line on stderr
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
   },
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-  let cli = Cli::parse();
-  let path: PathBuf = cli.file.unwrap_or("procon.yaml".into());
+impl Cli {
+  fn verbosity(&self) -> Verbosity {
+    if self.quiet {
+      Verbosity::Quiet
+    } else if self.verbose {
+      Verbosity::Verbose
+    } else {
+      Verbosity::Normal
+    }
+  }
+}
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum LogFormat {
+  /// Only procon's usual output
+  #[default]
+  Text,
+  /// JSON events on stderr, such as `step_start` and `phase_end`
+  Json,
+}
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum GraphFormat {
+  /// Graphviz DOT
+  #[default]
+  Dot,
+  /// A Mermaid flowchart
+  Mermaid,
+}
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum Format {
+  /// `would run` lines
+  #[default]
+  Text,
+  /// A JSON array with each command's project, phase, step, cwd, deps and
+  /// env
+  Json,
+}
+
+fn parse_jobs(value: &str) -> Result<usize, String> {
+  if value == "auto" {
+    return Ok(std::thread::available_parallelism().map_or(1, |n| n.get()));
+  }
+
+  match value.parse() {
+    Ok(0) => Err("must be at least 1".into()),
+    Ok(jobs) => Ok(jobs),
+    Err(_) => Err(format!("expected a number or `auto`, got `{value}`")),
+  }
+}
+
+fn parse_arg(value: &str) -> Result<(String, String), String> {
+  match value.split_once('=') {
+    Some(("", _)) => Err("the key is empty".into()),
+    Some((key, value)) => Ok((key.to_string(), value.to_string())),
+    None => Err(format!("expected KEY=VALUE, got `{value}`")),
+  }
+}
+
+fn main() -> ExitCode {
+  match run(Cli::parse()) {
+    Ok(code) => code,
+    Err(e) => ExitCode::from(report(&e)),
+  }
+}
+
+/// Prints `e` and returns the exit code it maps to.
+fn report(e: &ProconError) -> u8 {
+  eprintln!("{} {e}", "error:".red());
+  e.exit_code()
+}
 
-  let instance = Instance::try_init(path).unwrap();
+fn run(cli: Cli) -> Result<ExitCode, ProconError> {
+  let verbosity = cli.verbosity();
+  let paths: Vec<PathBuf> = if cli.file.is_empty() {
+    vec!["procon.yaml".into()]
+  } else {
+    cli.file
+  };
+  if let Commands::Init { force } = cli.command {
+    init::write(&paths[0], force)?;
+    println!("wrote {}", paths[0].display());
+    return Ok(ExitCode::SUCCESS);
+  }
+  // Requests run in the client's working dir, so the daemon's paths can't
+  // be relative, and the socket is named after the absolute ones.
+  let absolute = paths
+    .iter()
+    .map(std::path::absolute)
+    .collect::<Result<Vec<_>, _>>()?;
+  let state_dir = state::state_dir(&paths[0]);
+  let socket = state::daemon_socket(&state_dir, &absolute);
+
+  if matches!(cli.command, Commands::Run { .. })
+    && let Some(code) = daemon::forward(
+      &socket,
+      &daemon::Request::new(std::env::args().skip(1).collect())?,
+    )?
+  {
+    return Ok(ExitCode::from(code));
+  }
 
-  match cli.command {
+  if let Commands::Daemon = cli.command {
+    let paths = absolute;
+    let state_dir = std::path::absolute(&state_dir)?;
+    let mut instance = Instance::try_init_many(paths.clone())?;
+    let mut loaded = state::config_hash(&instance.input_files()).ok();
+    daemon::serve(&socket, |request, cancel| {
+      if let Err(e) = reload_if_changed(&mut instance, &mut loaded, &paths) {
+        return report(&e);
+      }
+      serve_request(&instance, &state_dir, request, cancel)
+    })?;
+    return Ok(ExitCode::SUCCESS);
+  }
+
+  // `validate` reports the problems loading would otherwise stop at.
+  let instance = if matches!(cli.command, Commands::Validate) {
+    Instance::load_many(paths.clone())?
+  } else {
+    Instance::try_init_many(paths.clone())?
+  };
+  let Some(config_hash) =
+    check_config_hash(&cli.command, &instance, &state_dir)?
+  else {
+    return Ok(ExitCode::SUCCESS);
+  };
+  run::cancel_on_signals();
+  dispatch(
+    &instance,
+    cli.command,
+    verbosity,
+    &state_dir,
+    config_hash,
+    RunOptions::default(),
+  )?;
+  if run::interrupted() {
+    return Ok(ExitCode::from(130));
+  }
+  Ok(ExitCode::SUCCESS)
+}
+
+/// Handles `--only-if-config-changed`. Returns `None` if the run should be
+/// skipped, or else the config hash to save once it completes. Runs with
+/// different phases, projects, tags or args are tracked separately.
+fn check_config_hash(
+  command: &Commands,
+  instance: &Instance,
+  state_dir: &Path,
+) -> Result<Option<Option<ConfigHash>>, ProconError> {
+  let Commands::Run {
+    only_if_config_changed: true,
+    projects,
+    phases,
+    global,
+    args,
+    resume_from,
+    tag,
+    all_tags,
+    with_needs,
+    ..
+  } = command
+  else {
+    return Ok(Some(None));
+  };
+
+  let sorted = |items: &[String]| {
+    let mut items = items.to_vec();
+    items.sort();
+    items.dedup();
+    items
+  };
+  let mut args = args.clone();
+  args.sort();
+  let key = format!(
+    "{:?}",
+    (
+      phases,
+      sorted(projects),
+      global,
+      args,
+      resume_from,
+      sorted(tag),
+      all_tags,
+      with_needs,
+    )
+  );
+  let hash = ConfigHash::new(&instance.input_files(), &key)
+    .map_err(|e| ProconError::Config(format!("cannot read config: {e}")))?;
+  if hash.is_unchanged(state_dir) {
+    println!("config unchanged since the last run, skipping.");
+    return Ok(None);
+  }
+  Ok(Some(Some(hash)))
+}
+
+/// Reloads the daemon's config if it changed on disk since it was loaded.
+/// A config that no longer loads fails the request, and is tried again on
+/// the next one.
+fn reload_if_changed(
+  instance: &mut Instance,
+  loaded: &mut Option<u64>,
+  paths: &[PathBuf],
+) -> Result<(), ProconError> {
+  if state::config_hash(&instance.input_files()).ok() != *loaded {
+    *instance = Instance::try_init_many(paths.to_vec())?;
+    *loaded = state::config_hash(&instance.input_files()).ok();
+  }
+  Ok(())
+}
+
+/// Runs a command forwarded by a client against the daemon's loaded config,
+/// returning the exit code for the client.
+fn serve_request(
+  instance: &Instance,
+  state_dir: &Path,
+  request: daemon::Request,
+  cancel: &CancellationToken,
+) -> u8 {
+  let args = std::iter::once("procon".to_string()).chain(request.args);
+  let cli = match Cli::try_parse_from(args) {
+    Ok(cli) => cli,
+    Err(e) => {
+      let _ = e.print();
+      return e.exit_code() as u8;
+    }
+  };
+
+  let verbosity = cli.verbosity();
+  let result =
+    check_config_hash(&cli.command, instance, state_dir).and_then(|hash| {
+      match hash {
+        Some(hash) => dispatch(
+          instance,
+          cli.command,
+          verbosity,
+          state_dir,
+          hash,
+          RunOptions {
+            cancel: cancel.clone(),
+            ..Default::default()
+          },
+        ),
+        None => Ok(()),
+      }
+    });
+  match result {
+    Ok(()) => 0,
+    Err(e) => report(&e),
+  }
+}
+
+fn dispatch(
+  instance: &Instance,
+  command: Commands,
+  verbosity: Verbosity,
+  state_dir: &Path,
+  config_hash: Option<ConfigHash>,
+  base: RunOptions,
+) -> Result<(), ProconError> {
+  match command {
+    Commands::Daemon => {
+      return Err(ProconError::Config("already running as a daemon".into()));
+    }
+    Commands::Init { .. } => {
+      return Err(ProconError::Config(
+        "init can't run through the daemon".into(),
+      ));
+    }
     Commands::Debug => {
       println!("{:#?}", instance);
     }
+    Commands::List { projects, json } => {
+      let filter = (!projects.is_empty()).then_some(projects.as_slice());
+      instance.cmd_list(filter, json)?;
+    }
+    Commands::Describe { project } => {
+      instance.cmd_describe(&project)?;
+    }
+    Commands::Validate => {
+      instance.cmd_validate()?;
+    }
+    Commands::Graph { format } => {
+      print!("{}", instance.graph(matches!(format, GraphFormat::Mermaid)));
+    }
+    Commands::PrintResolvedPath { project, cwd } => {
+      instance.cmd_print_resolved_path(&project, cwd.as_deref())?;
+    }
+    Commands::Clean => {
+      instance.cmd_clean()?;
+    }
+    Commands::PrintCommand {
+      project,
+      phase,
+      step,
+    } => {
+      instance.cmd_print_command(&project, &phase, step)?;
+    }
     Commands::Run {
       projects,
       phases,
       global,
+      args,
       dry_run,
+      format,
+      resume_from,
+      quiet_on_success,
+      no_banner,
+      filter_failed_only,
+      strict_substitution,
+      clear_on_success,
+      manifest,
+      tail_on_failure,
+      randomize_order,
+      pty,
+      only_if_config_changed: _,
+      fail_on_warning,
+      gc_roots,
+      banner_format,
+      explain_deps,
+      jobs,
+      keep_going,
+      per_project,
+      tag,
+      all_tags,
+      with_needs,
+      no_nix,
+      log_file,
+      log_format,
     } => {
+      if explain_deps {
+        let filter = (!projects.is_empty()).then_some(projects.as_slice());
+        return instance.cmd_explain_deps(&phases, filter, global);
+      }
+
+      let shuffle_seed = randomize_order.map(|seed| {
+        seed.unwrap_or_else(|| {
+          SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default()
+        })
+      });
+
+      let opts = RunOptions {
+        dry_run,
+        plan_json: matches!(format, Format::Json),
+        quiet_on_success,
+        no_banner,
+        banner_format,
+        failed_only: filter_failed_only,
+        strict_substitution,
+        fail_on_warning,
+        clear_on_success,
+        manifest,
+        tail_on_failure,
+        pty,
+        gc_roots: gc_roots.then(|| state::gc_roots_dir(state_dir)),
+        jobs,
+        shuffle_seed,
+        verbosity,
+        keep_going,
+        per_project,
+        tags: tag,
+        all_tags,
+        with_needs,
+        no_nix,
+        log: log_file
+          .map(|path| {
+            let path = instance.config_dir().join(path);
+            CommandLog::open(&path).map_err(|e| {
+              ProconError::Config(format!(
+                "cannot open log file {}: {e}",
+                path.display()
+              ))
+            })
+          })
+          .transpose()?,
+        events: match log_format {
+          LogFormat::Text => None,
+          LogFormat::Json => Some(Arc::new(JsonEmitter)),
+        },
+        ..base
+      };
+
       if global {
         // Run global commands
-        instance.cmd_run_global(phases, dry_run).unwrap();
+        instance.cmd_run_global(phases, args.into_iter().collect(), &opts)?;
       } else {
         // Run project phases
         let project_filter = if projects.is_empty() {
@@ -63,7 +643,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
           Some(projects)
         };
 
-        instance.cmd_run(phases, project_filter, dry_run).unwrap();
+        instance.cmd_run(phases, project_filter, resume_from, &opts)?;
+      }
+
+      // Only a run that finished counts, so one that was cancelled (or
+      // interrupted by a signal, which cancels too) is redone next time.
+      if let Some(hash) = config_hash
+        && !dry_run
+        && !opts.cancel.is_cancelled()
+      {
+        hash.save(state_dir)?;
       }
     }
   }
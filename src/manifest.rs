@@ -0,0 +1,81 @@
+use std::{
+  fs,
+  path::Path,
+  process::{Command, Stdio},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{
+  config::Project,
+  run::{Outcome, Summary},
+};
+
+/// What a run produced for one project.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestEntry {
+  pub project: String,
+  pub status: &'static str,
+  /// The project dir's git commit, if it is a git checkout.
+  pub revision: Option<String>,
+  /// Seconds since the Unix epoch when the run finished.
+  pub built_at: u64,
+  pub artifact_path: String,
+}
+
+/// Writes a JSON manifest describing every project that took part in the
+/// run summarized by `summary`.
+pub fn write_manifest(
+  path: &Path,
+  projects: &[(&String, &Project)],
+  summary: &Summary,
+) -> std::io::Result<()> {
+  let built_at = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or_default();
+
+  let entries: Vec<ManifestEntry> = projects
+    .iter()
+    .filter_map(|(name, project)| {
+      let status = match summary.project_outcome(name)? {
+        Outcome::Success => "success",
+        Outcome::Failed => "failed",
+        Outcome::Warning => "warning",
+        Outcome::Cancelled => "cancelled",
+        Outcome::Skipped => "skipped",
+      };
+      let artifact_path =
+        fs::canonicalize(&project.dir).unwrap_or_else(|_| project.dir.clone());
+
+      Some(ManifestEntry {
+        project: name.to_string(),
+        status,
+        revision: git_revision(&project.dir),
+        built_at,
+        artifact_path: artifact_path.to_string_lossy().to_string(),
+      })
+    })
+    .collect();
+
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  fs::write(path, serde_json::to_string_pretty(&entries)?)
+}
+
+fn git_revision(dir: &Path) -> Option<String> {
+  let output = Command::new("git")
+    .arg("-C")
+    .arg(dir)
+    .args(["rev-parse", "HEAD"])
+    .stderr(Stdio::null())
+    .output()
+    .ok()?;
+
+  output
+    .status
+    .success()
+    .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
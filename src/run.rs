@@ -0,0 +1,911 @@
+use std::{
+  collections::VecDeque,
+  fs::{File, OpenOptions},
+  io::{self, BufRead, BufReader, IsTerminal, Read, Write},
+  os::unix::process::{CommandExt, ExitStatusExt},
+  path::{Path, PathBuf},
+  process::{Child, Command, ExitStatus, Stdio},
+  sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+  },
+  thread::{self, JoinHandle},
+  time::{Duration, Instant, SystemTime},
+};
+
+use colored::Colorize;
+use serde::Serialize;
+
+use crate::events::{Emitter, Event};
+
+/// How often a running child is checked for completion or cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Set by the handler installed with [`cancel_on_signals`].
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a running command has been given the terminal.
+static TERMINAL_LENT: AtomicBool = AtomicBool::new(false);
+
+/// Makes SIGINT, SIGTERM and SIGHUP cancel every run in this process, so
+/// the running commands are killed along with everything they started.
+pub fn cancel_on_signals() {
+  extern "C" fn handle(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+  }
+
+  for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+    // SAFETY: the handler only stores to an atomic.
+    unsafe {
+      libc::signal(signal, handle as *const () as libc::sighandler_t);
+    }
+  }
+}
+
+/// Whether a signal (or an interrupted command) has cancelled the runs.
+pub fn interrupted() -> bool {
+  INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// A shared flag that stops a run when signaled. Clones share the same flag,
+/// so one can be handed to [`RunOptions`] and another kept by the embedder.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Stops launching new commands and kills the one currently running.
+  pub fn cancel(&self) {
+    self.0.store(true, Ordering::SeqCst);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.0.load(Ordering::SeqCst) || interrupted()
+  }
+}
+
+/// Gives the terminal to a command's process group while it runs, so it can
+/// still read from the terminal and gets Ctrl-C itself. Dropping the loan
+/// takes the terminal back.
+struct TerminalLoan;
+
+impl TerminalLoan {
+  /// Lends the terminal to `child`'s group, unless our stdin isn't a
+  /// terminal we own or another command already has it.
+  fn new(child: &Child) -> Option<Self> {
+    let fd = libc::STDIN_FILENO;
+    // SAFETY: these calls only query and set the terminal's process group.
+    unsafe {
+      if libc::isatty(fd) != 1 || libc::tcgetpgrp(fd) != libc::getpgrp() {
+        return None;
+      }
+      if TERMINAL_LENT.swap(true, Ordering::SeqCst) {
+        return None;
+      }
+      let group = child.id() as libc::pid_t;
+      if libc::tcsetpgrp(fd, group) != 0 {
+        TERMINAL_LENT.store(false, Ordering::SeqCst);
+        return None;
+      }
+      // It may have tried to read before it had the terminal, and been
+      // stopped for it.
+      libc::killpg(group, libc::SIGCONT);
+    }
+    Some(Self)
+  }
+}
+
+impl Drop for TerminalLoan {
+  fn drop(&mut self) {
+    // SAFETY: as above. We're a background group by now, so taking the
+    // terminal back would stop us with SIGTTOU unless it is ignored.
+    unsafe {
+      let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+      libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+      libc::signal(libc::SIGTTOU, previous);
+    }
+    TERMINAL_LENT.store(false, Ordering::SeqCst);
+  }
+}
+
+/// A file that every command run is appended to, with where it ran, how it
+/// ended and its output. Clones share the file.
+#[derive(Debug, Clone)]
+pub struct CommandLog {
+  file: Arc<Mutex<File>>,
+}
+
+impl CommandLog {
+  /// Opens `path` for appending, creating it if needed.
+  pub fn open(path: &Path) -> io::Result<Self> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(Self {
+      file: Arc::new(Mutex::new(file)),
+    })
+  }
+
+  /// Appends one command's record and flushes it, so it survives a crash
+  /// later in the run.
+  fn write(
+    &self,
+    opts: &RunOptions,
+    command: &str,
+    outcome: &str,
+    stdout: &[u8],
+    stderr: &[u8],
+  ) -> io::Result<()> {
+    let mut record = format!(
+      "[{}] {}/{} step {}: {outcome}\n$ {command}\n",
+      humantime::format_rfc3339_seconds(SystemTime::now()),
+      opts.project,
+      opts.phase,
+      opts.step,
+    );
+    for (name, output) in [("stdout", stdout), ("stderr", stderr)] {
+      if output.is_empty() {
+        continue;
+      }
+      record.push_str(&format!("{name}:\n"));
+      for line in String::from_utf8_lossy(output).lines() {
+        record.push_str(&format!("  {line}\n"));
+      }
+    }
+
+    let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+    file.write_all(record.as_bytes())?;
+    file.flush()
+  }
+}
+
+/// How much procon prints besides the commands' own output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+  /// Only errors: no `$ command` lines or status notes.
+  Quiet,
+  #[default]
+  Normal,
+  /// Also print each step's resolved deps, working dir and environment.
+  Verbose,
+}
+
+/// Flags that control how phases are executed and reported.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+  /// Print the commands instead of running them.
+  pub dry_run: bool,
+  /// With `dry_run`, print the plan as one JSON array instead of lines for
+  /// people.
+  pub plan_json: bool,
+  /// Buffer each command's output and only print it if the command fails.
+  pub quiet_on_success: bool,
+  /// Don't print the `$ command` line before each command.
+  pub no_banner: bool,
+  /// Template for the line printed before each command. `{command}` is
+  /// replaced by the command, and `{project}`, `{phase}` and `{cwd}` by
+  /// where it runs. Defaults to `$ {command}`.
+  pub banner_format: Option<String>,
+  /// Only list failures in the end-of-run summary.
+  pub failed_only: bool,
+  /// Refuse to run if any command still contains a `{{...}}` token after
+  /// substitution.
+  pub strict_substitution: bool,
+  /// Treat what would only be warnings (phases in `allow_failure` that
+  /// fail, and projects without a requested phase) as errors.
+  pub fail_on_warning: bool,
+  /// On a terminal, erase a command's output once it succeeds.
+  pub clear_on_success: bool,
+  /// Write a JSON manifest of the run's projects to this path.
+  pub manifest: Option<PathBuf>,
+  /// Buffer each command's output and, if it fails, print only this many
+  /// of its last lines.
+  pub tail_on_failure: Option<usize>,
+  /// Run each command under a pseudo-terminal so it keeps its terminal-only
+  /// output (colors, progress bars) when piped.
+  pub pty: bool,
+  /// How many times to rerun a failed command. Set from the step being run.
+  pub retries: u32,
+  /// Only retry when the command exits with one of these codes; empty means
+  /// any failure. Set from the step being run.
+  pub retry_on: Vec<i32>,
+  /// How long to wait before rerunning a failed command. Set from the step
+  /// being run.
+  pub retry_delay: Option<Duration>,
+  /// Kill a command that runs longer than this and count it as failed. Set
+  /// from the step being run.
+  pub timeout: Option<Duration>,
+  /// Fail steps that have nix deps instead of running them through
+  /// nix-shell, for machines without nix.
+  pub no_nix: bool,
+  /// Keep each step's nix deps alive with GC roots in this dir.
+  pub gc_roots: Option<PathBuf>,
+  /// How many projects may run a phase at once. Above 1, each command's
+  /// output is held back and printed as one block when it finishes.
+  pub jobs: usize,
+  /// Run all the requested phases for one project before moving to the
+  /// next, instead of one phase for every project at a time.
+  pub per_project: bool,
+  /// Only run projects with any of these tags (or all of them, with
+  /// `all_tags`).
+  pub tags: Vec<String>,
+  pub all_tags: bool,
+  /// Also run the projects that the selected ones need, directly or not.
+  pub with_needs: bool,
+  /// Shuffle the project order with this seed instead of sorting by name.
+  pub shuffle_seed: Option<u64>,
+  /// Keep running a phase's remaining steps, and the project's later
+  /// phases, after a step fails.
+  pub keep_going: bool,
+  /// Signaled to cancel the run.
+  pub cancel: CancellationToken,
+  pub verbosity: Verbosity,
+  /// Append every command, with its outcome and output, to this log.
+  pub log: Option<CommandLog>,
+  /// Also report phases and commands starting and ending to this emitter.
+  pub events: Option<Arc<dyn Emitter>>,
+  /// The project and phase being run, for the log and events. Set by
+  /// [`RunOptions::for_phase`].
+  pub project: String,
+  pub phase: String,
+  /// The (1-based) step being run, or 0 for a hook. Set by
+  /// [`RunOptions::for_step`].
+  pub step: usize,
+  /// The command being run as the step wrote it, for the banner, the log
+  /// and events. Set by
+  /// [`RunOptions::for_command`]; when empty, the process invocation is
+  /// shown instead.
+  pub command: String,
+}
+
+impl RunOptions {
+  /// Whether child processes should write straight to our terminal.
+  pub fn inherit(&self) -> bool {
+    !self.buffered()
+  }
+
+  /// Fills `{key}` in the banner format, leaving the other placeholders for
+  /// later.
+  pub fn with_banner_var(&self, key: &str, value: &str) -> RunOptions {
+    RunOptions {
+      banner_format: self
+        .banner_format
+        .as_ref()
+        .map(|format| format.replace(&format!("{{{key}}}"), value)),
+      ..self.clone()
+    }
+  }
+
+  /// Options for running `phase` of `project`: the banner's `{project}` and
+  /// `{phase}` are filled in and log records are attributed to them.
+  pub fn for_phase(&self, project: &str, phase: &str) -> RunOptions {
+    RunOptions {
+      project: project.to_string(),
+      phase: phase.to_string(),
+      ..self
+        .with_banner_var("project", project)
+        .with_banner_var("phase", phase)
+    }
+  }
+
+  /// Attributes log records to the given (1-based) step.
+  pub fn for_step(&self, step: usize) -> RunOptions {
+    RunOptions {
+      step,
+      ..self.clone()
+    }
+  }
+
+  /// Attributes the banner, log and events to the given command line.
+  pub fn for_command(&self, command: &str) -> RunOptions {
+    RunOptions {
+      command: command.to_string(),
+      ..self.clone()
+    }
+  }
+
+  /// The line shown for `command`: the step's command if it was given with
+  /// [`RunOptions::for_command`], or else the process invocation.
+  fn command_line(&self, command: &Command) -> String {
+    if self.command.is_empty() {
+      format!("{command:?}")
+    } else {
+      self.command.clone()
+    }
+  }
+
+  /// Whether a dry run prints its plan as JSON.
+  pub fn json_plan(&self) -> bool {
+    self.dry_run && self.plan_json
+  }
+
+  /// Whether the `$ command` line is printed before each command.
+  fn banner(&self) -> bool {
+    !self.no_banner && self.verbosity > Verbosity::Quiet
+  }
+
+  /// Whether output is held back until the command finishes.
+  fn buffered(&self) -> bool {
+    self.quiet_on_success || self.tail_on_failure.is_some() || self.grouped()
+  }
+
+  /// Whether a successful command's output is printed in one block, so
+  /// projects running in parallel don't interleave.
+  fn grouped(&self) -> bool {
+    self.jobs > 1 && !self.quiet_on_success && self.tail_on_failure.is_none()
+  }
+}
+
+/// The last `limit` lines a command printed.
+#[derive(Debug, Default)]
+struct Tail {
+  lines: VecDeque<Vec<u8>>,
+  limit: usize,
+}
+
+impl Tail {
+  fn new(limit: usize) -> Self {
+    Self {
+      lines: VecDeque::with_capacity(limit),
+      limit,
+    }
+  }
+
+  fn push(&mut self, line: Vec<u8>) {
+    if self.limit == 0 {
+      return;
+    }
+
+    if self.lines.len() == self.limit {
+      self.lines.pop_front();
+    }
+    self.lines.push_back(line);
+  }
+}
+
+/// How a child process ended.
+enum Exit {
+  Exited(ExitStatus),
+  /// Killed because the run was cancelled.
+  Cancelled,
+  /// Killed because it ran past its timeout.
+  TimedOut(Duration),
+}
+
+/// The result of running a command or phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+  Success,
+  Failed,
+  Cancelled,
+}
+
+/// What running one project phase did, for callers using procon as a
+/// library.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhaseOutcome {
+  pub status: Status,
+  /// Every command that ran, in order. Steps skipped by `if` or `unless`
+  /// have none.
+  pub commands: Vec<CommandOutcome>,
+}
+
+/// One command of a phase and how it went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandOutcome {
+  /// The (1-based) number of the step the command belongs to, or 0 for a
+  /// `pre` or `post` hook.
+  pub step: usize,
+  pub command: String,
+  pub status: Status,
+  /// The command's stdout. Its stderr is printed as usual.
+  pub output: String,
+}
+
+/// How a project's phase ended, as reported in the run summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+  Success,
+  Failed,
+  /// The phase failed but is listed in the project's `allow_failure`, or
+  /// the project has no such phase.
+  Warning,
+  Cancelled,
+  /// The phase didn't run because a project it needs failed, was skipped or
+  /// is disabled.
+  Skipped,
+}
+
+/// The outcome of every project phase that ran, in order.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+  entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+  project: String,
+  phase: String,
+  outcome: Outcome,
+  /// The (1-based) numbers of the steps that failed.
+  failed_steps: Vec<usize>,
+}
+
+impl Summary {
+  pub fn record(
+    &mut self,
+    project: &str,
+    phase: &str,
+    outcome: Outcome,
+    failed_steps: Vec<usize>,
+  ) {
+    self.entries.push(Entry {
+      project: project.to_string(),
+      phase: phase.to_string(),
+      outcome,
+      failed_steps,
+    });
+  }
+
+  /// The worst outcome of any of `project`'s phases, if any of them ran.
+  pub fn project_outcome(&self, project: &str) -> Option<Outcome> {
+    self
+      .entries
+      .iter()
+      .filter(|entry| entry.project == project)
+      .map(|entry| entry.outcome)
+      .max_by_key(|outcome| match outcome {
+        Outcome::Success => 0,
+        Outcome::Warning => 1,
+        Outcome::Skipped => 2,
+        Outcome::Cancelled => 3,
+        Outcome::Failed => 4,
+      })
+  }
+
+  /// The projects with at least one failed phase, in the order they failed.
+  pub fn failed_projects(&self) -> Vec<&str> {
+    let mut projects = Vec::new();
+    for entry in self.entries.iter() {
+      if entry.outcome == Outcome::Failed
+        && !projects.contains(&entry.project.as_str())
+      {
+        projects.push(entry.project.as_str());
+      }
+    }
+    projects
+  }
+
+  pub fn print(&self, failed_only: bool) {
+    let entries: Vec<_> = self
+      .entries
+      .iter()
+      .filter(|entry| {
+        !failed_only
+          || matches!(entry.outcome, Outcome::Failed | Outcome::Skipped)
+      })
+      .collect();
+    if entries.is_empty() {
+      return;
+    }
+
+    println!("{}", "summary:".bold());
+    for entry in entries {
+      let label = match entry.outcome {
+        Outcome::Success => "ok".green(),
+        Outcome::Failed => "failed".red(),
+        Outcome::Warning => "warning".yellow(),
+        Outcome::Cancelled => "cancelled".yellow(),
+        Outcome::Skipped => "skipped".yellow(),
+      };
+      print!("  {label:<9} {} {}", entry.project, entry.phase);
+      match entry.failed_steps.as_slice() {
+        [] => println!(),
+        [step] => println!(" (step {step})"),
+        steps => {
+          let steps: Vec<_> = steps.iter().map(|s| s.to_string()).collect();
+          println!(" (steps {})", steps.join(", "));
+        }
+      }
+    }
+  }
+}
+
+/// Runs an assembled command, printing its banner and (depending on the
+/// options) its output.
+pub fn execute(command: &mut Command, opts: &RunOptions) -> io::Result<Status> {
+  run_child(command, opts, false).map(|(status, _)| status)
+}
+
+/// Like [`execute`], but captures the command's stdout instead of printing
+/// it and returns it alongside the status.
+pub fn execute_captured(
+  command: &mut Command,
+  opts: &RunOptions,
+) -> io::Result<(Status, Vec<u8>)> {
+  run_child(command, opts, true)
+}
+
+/// Runs the command, rerunning it as allowed by `opts.retries` and
+/// `opts.retry_on`.
+fn run_child(
+  command: &mut Command,
+  opts: &RunOptions,
+  capture: bool,
+) -> io::Result<(Status, Vec<u8>)> {
+  let mut attempt = 0;
+  loop {
+    let (status, code, stdout) = run_once(command, opts, capture)?;
+    let retryable = match code {
+      Some(code) => opts.retry_on.is_empty() || opts.retry_on.contains(&code),
+      None => opts.retry_on.is_empty(),
+    };
+    if status != Status::Failed || attempt >= opts.retries || !retryable {
+      return Ok((status, stdout));
+    }
+
+    if let Some(delay) = opts.retry_delay
+      && !sleep(delay, &opts.cancel)
+    {
+      return Ok((Status::Cancelled, stdout));
+    }
+
+    attempt += 1;
+    if opts.verbosity > Verbosity::Quiet {
+      println!(
+        "{}",
+        format!("retrying (attempt {} of {})", attempt + 1, opts.retries + 1)
+          .yellow()
+      );
+    }
+  }
+}
+
+/// Runs the command once, returning its exit code alongside the status.
+fn run_once(
+  command: &mut Command,
+  opts: &RunOptions,
+  capture: bool,
+) -> io::Result<(Status, Option<i32>, Vec<u8>)> {
+  if opts.cancel.is_cancelled() {
+    return Ok((Status::Cancelled, None, Vec::new()));
+  }
+
+  let line = opts.command_line(command);
+  let banner = match &opts.banner_format {
+    Some(format) => format.replace("{command}", &line),
+    None => format!("$ {line}"),
+  }
+  .bold();
+  if opts.buffered() {
+    command.stdout(Stdio::piped());
+    command.stderr(Stdio::piped());
+    command.stdin(Stdio::null());
+  } else if opts.banner() {
+    println!("{banner}");
+  }
+
+  // Clearing needs to know how many lines the child printed, and the log
+  // needs a copy of the output, so then it is forwarded through us rather
+  // than inherited.
+  let clear = opts.clear_on_success
+    && !opts.buffered()
+    && !capture
+    && io::stdout().is_terminal();
+  let keep = opts.log.is_some();
+  let forward = clear || (keep && !opts.buffered());
+  let lines = Arc::new(AtomicUsize::new(0));
+
+  if capture || forward {
+    command.stdout(Stdio::piped());
+  }
+  if forward {
+    command.stderr(Stdio::piped());
+  }
+
+  let tail = opts
+    .tail_on_failure
+    .map(|limit| Arc::new(Mutex::new(Tail::new(limit))));
+
+  if let Some(events) = &opts.events {
+    events.emit(&Event::step_start(opts, &line));
+  }
+  let started = Instant::now();
+
+  // Each command gets its own process group, so a timeout or cancellation
+  // kills everything it started and not just the shell.
+  let mut child = command.process_group(0).spawn()?;
+  // Only a command reading our stdin needs the terminal; lending it
+  // otherwise would just put us in the background.
+  let loan = if opts.buffered() {
+    None
+  } else {
+    TerminalLoan::new(&child)
+  };
+  let (stdout, stderr) = if let Some(tail) = &tail {
+    (
+      child.stdout.take().map(|out| {
+        if capture {
+          read_in_background(out)
+        } else {
+          tail_in_background(out, tail.clone(), keep)
+        }
+      }),
+      child
+        .stderr
+        .take()
+        .map(|err| tail_in_background(err, tail.clone(), keep)),
+    )
+  } else if forward {
+    (
+      child.stdout.take().map(|out| {
+        if capture {
+          read_in_background(out)
+        } else {
+          forward_in_background(out, io::stdout(), lines.clone(), keep)
+        }
+      }),
+      child.stderr.take().map(|err| {
+        forward_in_background(err, io::stderr(), lines.clone(), keep)
+      }),
+    )
+  } else {
+    (
+      child.stdout.take().map(read_in_background),
+      child.stderr.take().map(read_in_background),
+    )
+  };
+  let exit = wait(&mut child, opts, loan.is_some())?;
+  drop(loan);
+  let mut stdout = join_output(stdout);
+  if opts.pty && capture {
+    // The terminal translated newlines; undo that for captured values.
+    stdout.retain(|b| *b != b'\r');
+  }
+  let stderr = join_output(stderr);
+
+  if let Some(events) = &opts.events {
+    let status = match &exit {
+      Exit::Cancelled => Status::Cancelled,
+      Exit::Exited(status) if status.success() => Status::Success,
+      _ => Status::Failed,
+    };
+    events.emit(&Event::step_end(opts, &line, status, started.elapsed()));
+  }
+
+  if let Some(log) = &opts.log {
+    let outcome = match &exit {
+      Exit::Cancelled => "cancelled".to_string(),
+      Exit::TimedOut(timeout) => {
+        format!("timed out after {}", humantime::format_duration(*timeout))
+      }
+      Exit::Exited(status) => match status.code() {
+        Some(code) => format!("exit {code}"),
+        None => "killed by a signal".to_string(),
+      },
+    };
+    if let Err(e) = log.write(opts, &line, &outcome, &stdout, &stderr) {
+      println!("warning: cannot write to the log: {e}");
+    }
+  }
+
+  let (status, failed) = match exit {
+    Exit::Cancelled => return Ok((Status::Cancelled, None, stdout)),
+    Exit::TimedOut(timeout) => (
+      None,
+      format!("timed out after {}.", humantime::format_duration(timeout)),
+    ),
+    Exit::Exited(status) => (Some(status), "failed.".to_string()),
+  };
+
+  match status {
+    Some(status) if status.success() => {
+      let lines = lines.load(Ordering::SeqCst);
+      if clear && lines > 0 {
+        // Move to the start of the first output line and erase downwards.
+        print!("\x1b[{lines}F\x1b[J");
+        io::stdout().flush()?;
+      }
+
+      if opts.grouped() {
+        let mut block = Vec::new();
+        if opts.banner() {
+          block.extend(format!("{banner}\n").into_bytes());
+        }
+        if !capture {
+          block.extend_from_slice(&stdout);
+        }
+        block.extend_from_slice(&stderr);
+        io::stdout().lock().write_all(&block)?;
+      }
+
+      Ok((Status::Success, Some(0), stdout))
+    }
+    status => {
+      let code = status.and_then(|status| status.code());
+      if opts.grouped() {
+        let mut block = Vec::new();
+        if opts.banner() {
+          block.extend(format!("{banner}\n").into_bytes());
+        }
+        block.extend_from_slice(&stdout);
+        block.extend_from_slice(&stderr);
+        block.extend(format!("{failed}\n").into_bytes());
+        io::stdout().lock().write_all(&block)?;
+        return Ok((Status::Failed, code, stdout));
+      }
+
+      if opts.buffered() && opts.banner() {
+        println!("{banner}");
+      }
+
+      if let Some(tail) = tail {
+        let mut tail = tail.lock().unwrap_or_else(|e| e.into_inner());
+        // Captured output bypassed the tail; the rest is already in it.
+        if capture {
+          for line in stdout.split_inclusive(|b| *b == b'\n') {
+            tail.push(line.to_vec());
+          }
+        }
+
+        let mut out = io::stdout();
+        for line in tail.lines.iter() {
+          out.write_all(line)?;
+        }
+      } else if !forward {
+        io::stdout().write_all(&stdout)?;
+        io::stderr().write_all(&stderr)?;
+      } else if capture {
+        // Only stderr was forwarded as it arrived.
+        io::stdout().write_all(&stdout)?;
+      }
+
+      println!("{failed}");
+      Ok((Status::Failed, code, stdout))
+    }
+  }
+}
+
+/// Waits for `child` to exit, killing it if the run is cancelled or the
+/// command's timeout passes first.
+fn wait(
+  child: &mut Child,
+  opts: &RunOptions,
+  has_terminal: bool,
+) -> io::Result<Exit> {
+  let deadline = opts.timeout.map(|timeout| Instant::now() + timeout);
+  loop {
+    if let Some(status) = child.try_wait()? {
+      // Ctrl-C went to the command rather than to us, but it is meant for
+      // the whole run. Commands that trap it (such as `docker run`) exit
+      // with the shell's code for it instead of being killed by it.
+      let interrupted = status.signal() == Some(libc::SIGINT)
+        || status.code() == Some(128 + libc::SIGINT);
+      if has_terminal && interrupted {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        return Ok(Exit::Cancelled);
+      }
+      return Ok(Exit::Exited(status));
+    }
+
+    if opts.cancel.is_cancelled() {
+      kill_group(child)?;
+      return Ok(Exit::Cancelled);
+    }
+
+    if let (Some(deadline), Some(timeout)) = (deadline, opts.timeout)
+      && Instant::now() >= deadline
+    {
+      kill_group(child)?;
+      return Ok(Exit::TimedOut(timeout));
+    }
+
+    thread::sleep(POLL_INTERVAL);
+  }
+}
+
+/// Kills `child` and the rest of its process group, then reaps it.
+fn kill_group(child: &mut Child) -> io::Result<()> {
+  // The child leads its group, so the group's id is its pid.
+  // SAFETY: killpg only sends a signal.
+  if unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) } != 0 {
+    child.kill()?;
+  }
+  child.wait()?;
+  Ok(())
+}
+
+/// Sleeps for `duration`, waking early if the run is cancelled. Returns
+/// whether the whole duration passed.
+fn sleep(duration: Duration, cancel: &CancellationToken) -> bool {
+  let deadline = Instant::now() + duration;
+  while Instant::now() < deadline {
+    if cancel.is_cancelled() {
+      return false;
+    }
+    thread::sleep(POLL_INTERVAL.min(deadline - Instant::now()));
+  }
+  true
+}
+
+fn read_in_background<R>(mut reader: R) -> JoinHandle<Vec<u8>>
+where
+  R: Read + Send + 'static,
+{
+  thread::spawn(move || {
+    let mut buf = Vec::new();
+    let _ = reader.read_to_end(&mut buf);
+    buf
+  })
+}
+
+/// Copies `reader` to `writer` as it arrives, counting the lines written.
+/// With `keep`, a copy of everything is also returned.
+fn forward_in_background<R, W>(
+  mut reader: R,
+  mut writer: W,
+  lines: Arc<AtomicUsize>,
+  keep: bool,
+) -> JoinHandle<Vec<u8>>
+where
+  R: Read + Send + 'static,
+  W: Write + Send + 'static,
+{
+  thread::spawn(move || {
+    let mut kept = Vec::new();
+    let mut buf = [0; 8192];
+    while let Ok(n) = reader.read(&mut buf) {
+      if n == 0 {
+        break;
+      }
+
+      let newlines = buf[..n].iter().filter(|b| **b == b'\n').count();
+      lines.fetch_add(newlines, Ordering::SeqCst);
+      let _ = writer.write_all(&buf[..n]);
+      let _ = writer.flush();
+      if keep {
+        kept.extend_from_slice(&buf[..n]);
+      }
+    }
+    kept
+  })
+}
+
+/// Keeps only the last lines of `reader` in `tail`. With `keep`, all of it
+/// is also returned.
+fn tail_in_background<R>(
+  reader: R,
+  tail: Arc<Mutex<Tail>>,
+  keep: bool,
+) -> JoinHandle<Vec<u8>>
+where
+  R: Read + Send + 'static,
+{
+  thread::spawn(move || {
+    let mut kept = Vec::new();
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    while let Ok(n) = reader.read_until(b'\n', &mut line) {
+      if n == 0 {
+        break;
+      }
+
+      if keep {
+        kept.extend_from_slice(&line);
+      }
+      tail
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(std::mem::take(&mut line));
+    }
+    kept
+  })
+}
+
+fn join_output(handle: Option<JoinHandle<Vec<u8>>>) -> Vec<u8> {
+  handle
+    .map(|handle| handle.join().unwrap_or_default())
+    .unwrap_or_default()
+}
@@ -0,0 +1,119 @@
+use std::{
+  fs, io,
+  path::{Path, PathBuf},
+};
+
+/// The directory procon keeps state in between runs: `.procon` next to the
+/// config file.
+pub fn state_dir(config_path: &Path) -> PathBuf {
+  config_path
+    .parent()
+    .unwrap_or_else(|| Path::new("."))
+    .join(".procon")
+}
+
+/// FNV-1a, used because unlike std's hasher it is stable across builds.
+#[derive(Debug, Clone, Copy)]
+struct Fnv(u64);
+
+impl Fnv {
+  fn new() -> Self {
+    Self(0xcbf2_9ce4_8422_2325)
+  }
+
+  fn write(&mut self, bytes: &[u8]) {
+    for byte in bytes {
+      self.0 ^= u64::from(*byte);
+      self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+    }
+  }
+}
+
+/// A hash of the given files' contents, in order. A missing file hashes
+/// differently from an empty one, so creating it counts as a change.
+pub fn config_hash(paths: &[PathBuf]) -> io::Result<u64> {
+  let mut hash = Fnv::new();
+  for path in paths {
+    match fs::read(path) {
+      Ok(content) => {
+        hash.write(&(content.len() as u64 + 1).to_le_bytes());
+        hash.write(&content);
+      }
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        hash.write(&0u64.to_le_bytes());
+      }
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(hash.0)
+}
+
+/// The config hash of a run, saved under a key for what it ran so that runs
+/// of different phases or projects are tracked separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigHash {
+  key: u64,
+  hash: u64,
+}
+
+impl ConfigHash {
+  /// Hashes `paths` for a run described by `key`.
+  pub fn new(paths: &[PathBuf], key: &str) -> io::Result<Self> {
+    let mut key_hash = Fnv::new();
+    key_hash.write(key.as_bytes());
+    Ok(Self {
+      key: key_hash.0,
+      hash: config_hash(paths)?,
+    })
+  }
+
+  fn path(&self, state_dir: &Path) -> PathBuf {
+    state_dir
+      .join("config-hashes")
+      .join(format!("{:016x}", self.key))
+  }
+
+  /// Whether the last successful run with the same key saw the same config.
+  pub fn is_unchanged(&self, state_dir: &Path) -> bool {
+    fs::read_to_string(self.path(state_dir))
+      .ok()
+      .and_then(|content| u64::from_str_radix(content.trim(), 16).ok())
+      == Some(self.hash)
+  }
+
+  pub fn save(&self, state_dir: &Path) -> io::Result<()> {
+    let path = self.path(state_dir);
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    fs::write(path, format!("{:016x}\n", self.hash))
+  }
+}
+
+/// The socket a daemon for the given (absolute) config files listens on.
+/// Every file is part of its name, so a daemon only serves invocations that
+/// load the same files in the same order.
+pub fn daemon_socket(state_dir: &Path, configs: &[PathBuf]) -> PathBuf {
+  let mut hash = Fnv::new();
+  for config in configs {
+    hash.write(config.as_os_str().as_encoded_bytes());
+    hash.write(&[0]);
+  }
+  state_dir.join(format!("daemon-{:016x}.sock", hash.0))
+}
+
+/// Where GC roots for nix-shell dep environments are kept.
+pub fn gc_roots_dir(state_dir: &Path) -> PathBuf {
+  state_dir.join("gcroots")
+}
+
+/// The GC root for one project's set of deps, named so that steps with the
+/// same deps share it.
+pub fn gc_root(dir: &Path, project: &str, deps: &[String]) -> PathBuf {
+  let mut hash = Fnv::new();
+  for dep in deps {
+    hash.write(dep.as_bytes());
+    hash.write(&[0]);
+  }
+  dir.join(format!("{project}-{:016x}", hash.0))
+}
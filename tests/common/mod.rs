@@ -0,0 +1,43 @@
+// Each test crate compiles this module separately and uses only part of it.
+#![allow(dead_code)]
+
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+use procon::instance::Instance;
+use tempfile::TempDir;
+
+/// A temporary directory holding a `procon.yaml` and its project dirs.
+pub struct Fixture {
+  dir: TempDir,
+}
+
+impl Fixture {
+  /// Writes `config` as `procon.yaml`. Every `$ROOT` in it is replaced by
+  /// the fixture's directory.
+  pub fn new(config: &str) -> Self {
+    let dir = TempDir::new().expect("cannot create temp dir");
+    let config = config.replace("$ROOT", &dir.path().to_string_lossy());
+    fs::write(dir.path().join("procon.yaml"), config)
+      .expect("cannot write procon.yaml");
+
+    Self { dir }
+  }
+
+  /// Creates a directory (and its parents) inside the fixture.
+  pub fn mkdir(&self, name: &str) -> PathBuf {
+    let path = self.path(name);
+    fs::create_dir_all(&path).expect("cannot create dir");
+    path
+  }
+
+  pub fn path(&self, name: impl AsRef<Path>) -> PathBuf {
+    self.dir.path().join(name)
+  }
+
+  pub fn instance(&self) -> Instance {
+    Instance::try_init(self.path("procon.yaml")).expect("invalid config")
+  }
+}
@@ -0,0 +1,521 @@
+mod common;
+
+use common::Fixture;
+use procon::{
+  config::{Config, Project, ProjectFragment},
+  init,
+  instance::Instance,
+  run::RunOptions,
+};
+
+#[test]
+fn parse_error_names_file_and_line() {
+  let fixture = Fixture::new("projects:\n  app:\n    dir: [\n");
+
+  let err = Instance::try_init(fixture.path("procon.yaml")).unwrap_err();
+
+  assert_eq!(err.exit_code(), 2);
+  let message = err.to_string();
+  assert!(message.starts_with("failed to parse "), "{message}");
+  assert!(message.contains("procon.yaml"), "{message}");
+  assert!(message.contains("line"), "{message}");
+}
+
+#[test]
+fn invalid_env_names_are_rejected() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: .
+    env: { "A;rm -rf ~": x }
+"#,
+  );
+
+  let err = Instance::try_init(fixture.path("procon.yaml")).unwrap_err();
+
+  assert_eq!(err.exit_code(), 2);
+  assert_eq!(
+    err.to_string(),
+    "invalid environment variable name 'A;rm -rf ~'"
+  );
+}
+
+#[test]
+fn empty_and_space_separated_deps_are_reported() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: .
+    phases:
+      build:
+        steps:
+          - run: make
+            deps: ["", "a b"]
+"#,
+  );
+
+  let err = Instance::try_init(fixture.path("procon.yaml")).unwrap_err();
+
+  assert_eq!(err.exit_code(), 2);
+  let message = err.to_string();
+  assert!(
+    message.contains("project 'app', phase 'build', step 1: empty dep"),
+    "{message}"
+  );
+  assert!(
+    message.contains("dep 'a b' contains whitespace"),
+    "{message}"
+  );
+}
+
+#[test]
+fn validate_lists_every_problem() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    phases:
+      build:
+        steps:
+          - task: missing
+          - run: make
+            cwd: nowhere
+          - task: greet
+  gone:
+    dir: $ROOT/gone
+tasks:
+  greet:
+    args: [who]
+    steps:
+      - run: echo hello {{who}}
+"#,
+  );
+  fixture.mkdir("app");
+
+  let err = fixture.instance().cmd_validate().unwrap_err();
+
+  assert_eq!(err.to_string(), "4 problem(s) found");
+  assert_eq!(err.exit_code(), 2);
+}
+
+#[test]
+fn validate_lists_problems_that_stop_loading() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    env:
+      BAD-NAME: x
+    phases:
+      build:
+        steps:
+          - run: make
+            cwd: ../elsewhere
+          - run: make
+            deps: [""]
+"#,
+  );
+  fixture.mkdir("app");
+  fixture.mkdir("elsewhere");
+  let path = fixture.path("procon.yaml");
+  assert!(Instance::try_init(path.clone()).is_err());
+
+  let err = Instance::load_many(vec![path])
+    .unwrap()
+    .cmd_validate()
+    .unwrap_err();
+
+  assert_eq!(err.to_string(), "3 problem(s) found");
+}
+
+#[test]
+fn cwd_outside_the_project_dir_is_rejected() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    phases:
+      build:
+        steps:
+          - run: cat passwd
+            cwd: sub/../../../etc
+"#,
+  );
+
+  let err = Instance::try_init(fixture.path("procon.yaml")).unwrap_err();
+
+  assert_eq!(
+    err.to_string(),
+    "project 'app': cwd sub/../../../etc is outside the project dir (add it \
+     to allow_cwd if intended)"
+  );
+  assert_eq!(err.exit_code(), 2);
+}
+
+#[test]
+fn allow_cwd_permits_leaving_the_project_dir() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    allow_cwd: [..]
+    phases:
+      clone:
+        steps:
+          - run: mkdir app
+            cwd: ..
+"#,
+  );
+
+  fixture
+    .instance()
+    .cmd_run(vec!["clone".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert!(fixture.path("app").is_dir());
+}
+
+#[test]
+fn cwd_under_the_project_dir_may_be_absolute() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    phases:
+      build:
+        steps:
+          - run: touch built
+            cwd: "{{PROJECT_DIR}}/sub"
+"#,
+  );
+  let sub = fixture.mkdir("app/sub");
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert!(sub.join("built").exists());
+}
+
+#[test]
+fn global_commands_may_leave_the_config_dir() {
+  let fixture = Fixture::new(
+    r#"
+global:
+  up:
+    - run: touch $ROOT/ran
+      cwd: ..
+"#,
+  );
+
+  fixture
+    .instance()
+    .cmd_run_global(vec!["up".into()], [].into(), &RunOptions::default())
+    .unwrap();
+
+  assert!(fixture.path("ran").exists());
+}
+
+#[test]
+fn missing_cwd_fails_the_step() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    phases:
+      build:
+        steps:
+          - run: touch built
+            cwd: nowhere
+"#,
+  );
+  let app = fixture.mkdir("app");
+
+  let err = fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap_err();
+
+  assert_eq!(err.exit_code(), 1);
+  assert!(!app.join("built").exists());
+}
+
+#[test]
+fn cwd_expands_builtin_vars() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    phases:
+      build:
+        steps:
+          - run: touch built
+            cwd: subdirs/{{PROJECT_NAME}}
+            if: test -d {{PROJECT_DIR}}/subdirs
+"#,
+  );
+  let dir = fixture.mkdir("app/subdirs/app");
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert!(dir.join("built").exists());
+}
+
+#[test]
+fn config_round_trips_through_yaml() {
+  let yaml = r#"
+projects:
+  app:
+    dir: app
+    tags: [web]
+    nix: { mode: flake, flake: ".#dev" }
+    phases:
+      build:
+        persist_env: true
+        pre: [{ run: echo start }]
+        steps:
+          - run: make
+            deps: [gnumake]
+            timeout: 5m
+            if: test -f Makefile
+          - run: [make test, make lint]
+            cwd: sub
+            env: { CI: "1" }
+          - task: greet
+            with: { who: [a, b] }
+          - argv: [ls, -la]
+            shell: false
+tasks:
+  greet:
+    args: [who]
+    steps:
+      - run: echo {{who}}
+global:
+  tidy:
+    - run: rm -rf tmp
+  deploy:
+    steps: [{ run: ./deploy }]
+    env: { STAGE: prod }
+default_phases: [build]
+"#;
+
+  let config: Config = serde_norway::from_str(yaml).unwrap();
+  let written = serde_norway::to_string(&config).unwrap();
+  let reread: Config = serde_norway::from_str(&written).unwrap();
+
+  assert_eq!(reread, config);
+}
+
+#[test]
+fn init_writes_a_config_that_parses() {
+  let fixture = Fixture::new("");
+  let path = fixture.path("procon.yaml");
+
+  init::write(&path, true).unwrap();
+  let instance = Instance::try_init(path).unwrap();
+
+  assert_eq!(instance.plan_phase("app", "greet").unwrap().len(), 1);
+  let config: Config = serde_norway::from_str(init::TEMPLATE).unwrap();
+  let written = serde_norway::to_string(&config).unwrap();
+  assert_eq!(serde_norway::from_str::<Config>(&written).unwrap(), config);
+}
+
+#[test]
+fn init_refuses_to_overwrite_without_force() {
+  let fixture = Fixture::new("projects: {}\n");
+  let path = fixture.path("procon.yaml");
+
+  let err = init::write(&path, false).unwrap_err();
+
+  assert_eq!(err.exit_code(), 2);
+  assert_eq!(std::fs::read_to_string(path).unwrap(), "projects: {}\n");
+}
+
+#[test]
+fn relative_dirs_resolve_against_the_first_config_file() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: app
+    env_file: .env
+    phases:
+      build:
+        steps:
+          - run: echo $GREETING > out
+"#,
+  );
+  let app = fixture.mkdir("app");
+  std::fs::write(app.join(".env"), "GREETING=hello\n").unwrap();
+  let prod = fixture.mkdir("envs").join("prod.yaml");
+  std::fs::write(
+    &prod,
+    "projects:\n  web:\n    dir: web\n    phases:\n      build:\n        \
+     steps:\n          - run: touch built\n",
+  )
+  .unwrap();
+  let web = fixture.mkdir("web");
+  let elsewhere = tempfile::TempDir::new().unwrap();
+
+  let status = std::process::Command::new(env!("CARGO_BIN_EXE_procon"))
+    .current_dir(elsewhere.path())
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .arg("-f")
+    .arg(&prod)
+    .args(["run", "build", "--no-nix"])
+    .status()
+    .unwrap();
+
+  assert!(status.success());
+  assert_eq!(std::fs::read_to_string(app.join("out")).unwrap(), "hello\n");
+  assert!(web.join("built").exists());
+}
+
+#[test]
+fn fragments_merge_under_inline_values() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  web:
+    extend_from: web/procon.project.yaml
+    env: { MODE: live }
+"#,
+  );
+  let web = fixture.mkdir("web");
+  std::fs::write(
+    web.join("procon.project.yaml"),
+    r#"
+env: { MODE: test, GREETING: hello }
+phases:
+  build:
+    steps:
+      - run: echo $GREETING $MODE > out
+"#,
+  )
+  .unwrap();
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert_eq!(
+    std::fs::read_to_string(web.join("out")).unwrap(),
+    "hello live\n"
+  );
+}
+
+#[test]
+fn fragment_deps_are_added_after_inline_ones() {
+  let mut project = Project {
+    deps: vec!["git".into(), "jq".into()],
+    ..Default::default()
+  };
+
+  project.extend(ProjectFragment {
+    deps: vec!["jq".into(), "nodejs".into()],
+    ..Default::default()
+  });
+
+  assert_eq!(project.deps, ["git", "jq", "nodejs"]);
+}
+
+#[test]
+fn list_shows_projects_phases_tasks_and_globals() {
+  let fixture = Fixture::new(
+    r#"
+tasks:
+  fmt:
+    steps:
+      - run: cargo fmt
+global:
+  release:
+    - run: "true"
+projects:
+  web:
+    dir: $ROOT
+    tags: [frontend]
+    phases:
+      test: { steps: [] }
+      build: { steps: [] }
+  api:
+    dir: $ROOT
+    enabled: false
+"#,
+  );
+  let list = |json: bool| {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_procon"))
+      .arg("-f")
+      .arg(fixture.path("procon.yaml"))
+      .arg("list")
+      .args(json.then_some("--json"))
+      .output()
+      .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+  };
+
+  let text = list(false);
+  let json: serde_json::Value = serde_json::from_str(&list(true)).unwrap();
+
+  assert!(
+    text.contains("  api (disabled): \n  web [frontend]: build, test\n"),
+    "{text}"
+  );
+  assert!(text.contains("tasks: fmt\n"), "{text}");
+  assert!(text.contains("global: release\n"), "{text}");
+  assert_eq!(json["projects"][1]["name"], "web");
+  assert_eq!(
+    json["projects"][1]["phases"],
+    serde_json::json!(["build", "test"])
+  );
+  assert_eq!(json["projects"][0]["enabled"], false);
+  assert_eq!(json["global"], serde_json::json!(["release"]));
+}
+
+#[test]
+fn dep_sets_from_later_files_replace_earlier_ones() {
+  let mut config: Config = serde_norway::from_str(
+    "dep_sets:\n  web: [jq]\n  tools: [git]\nprojects: {}\n",
+  )
+  .unwrap();
+  let other: Config = serde_norway::from_str(
+    "dep_sets:\n  web: [nodejs, \"@tools\", git]\nprojects: {}\n",
+  )
+  .unwrap();
+
+  config.merge(other);
+
+  assert_eq!(
+    config
+      .resolve_deps(&["@web".into(), "curl".into()])
+      .unwrap(),
+    ["nodejs", "git", "curl"]
+  );
+}
+
+#[test]
+fn dep_set_cycles_are_reported() {
+  let config: Config = serde_norway::from_str(
+    "dep_sets:\n  a: [jq, \"@b\"]\n  b: [\"@a\"]\nprojects: {}\n",
+  )
+  .unwrap();
+
+  let err = config.resolve_deps(&["@a".into()]).unwrap_err();
+
+  assert_eq!(err, "dep set cycle: a -> b -> a");
+}
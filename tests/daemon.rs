@@ -0,0 +1,174 @@
+mod common;
+
+use std::{
+  collections::BTreeMap,
+  env, fs,
+  path::PathBuf,
+  process::{Child, Command, Stdio},
+  sync::{Arc, Mutex},
+  thread,
+  time::{Duration, Instant},
+};
+
+use common::Fixture;
+use procon::{
+  daemon::{self, Request},
+  state,
+};
+
+/// Starts `procon daemon` for the fixture's config and waits until it
+/// listens, returning it with the path of its socket.
+fn start_daemon(fixture: &Fixture) -> (Child, PathBuf) {
+  let config = fixture.path("procon.yaml");
+  let socket = state::daemon_socket(
+    &state::state_dir(&config),
+    std::slice::from_ref(&config),
+  );
+  let daemon = Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(&config)
+    .arg("daemon")
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()
+    .unwrap();
+
+  let deadline = Instant::now() + Duration::from_secs(10);
+  while !socket.exists() {
+    assert!(Instant::now() < deadline, "the daemon never started");
+    thread::sleep(Duration::from_millis(20));
+  }
+  (daemon, socket)
+}
+
+/// A client invocation of `procon run` for the fixture's config.
+fn client(fixture: &Fixture, phase: &str) -> Command {
+  let mut command = Command::new(env!("CARGO_BIN_EXE_procon"));
+  command
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", phase]);
+  command
+}
+
+#[test]
+fn forward_sends_the_request_and_returns_its_exit_code() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let socket = dir.path().join("daemon.sock");
+  let seen = Arc::new(Mutex::new(None));
+
+  let server = {
+    let socket = socket.clone();
+    let seen = seen.clone();
+    move || {
+      daemon::serve(&socket, |request, _| {
+        *seen.lock().unwrap() = Some((
+          request.args,
+          env::current_dir().unwrap(),
+          env::var("PROCON_TEST_MARK").ok(),
+        ));
+        7
+      })
+    }
+  };
+  thread::spawn(server);
+  let deadline = Instant::now() + Duration::from_secs(10);
+  while !socket.exists() {
+    assert!(Instant::now() < deadline, "the daemon never started");
+    thread::sleep(Duration::from_millis(20));
+  }
+
+  let cwd = dir.path().canonicalize().unwrap();
+  let request = Request {
+    args: vec!["run".into(), "build".into()],
+    cwd: cwd.clone(),
+    env: BTreeMap::from([("PROCON_TEST_MARK".into(), "x".into())]),
+  };
+  let code = daemon::forward(&socket, &request).unwrap();
+
+  assert_eq!(code, Some(7));
+  let (args, served_in, mark) = seen.lock().unwrap().take().unwrap();
+  assert_eq!(args, ["run", "build"]);
+  assert_eq!(served_in.canonicalize().unwrap(), cwd);
+  assert_eq!(mark.as_deref(), Some("x"));
+  assert!(env::var("PROCON_TEST_MARK").is_err());
+}
+
+#[test]
+fn forward_finds_no_daemon_without_a_socket() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let request = Request::new(vec!["run".into()]).unwrap();
+
+  let code = daemon::forward(&dir.path().join("daemon.sock"), &request);
+
+  assert_eq!(code.unwrap(), None);
+}
+
+#[test]
+fn runs_are_served_by_the_daemon() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: echo "$MARK" > mark && echo $PPID > served-by
+      broken:
+        steps:
+          - run: "false"
+"#,
+  );
+  let (mut daemon, _) = start_daemon(&fixture);
+
+  let output = client(&fixture, "build").env("MARK", "x").output().unwrap();
+  let failed = client(&fixture, "broken").output().unwrap();
+  daemon.kill().unwrap();
+  daemon.wait().unwrap();
+
+  assert!(output.status.success());
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(stdout.contains("ok        app build"), "{stdout}");
+  assert_eq!(fs::read_to_string(fixture.path("mark")).unwrap(), "x\n");
+  let served_by = fs::read_to_string(fixture.path("served-by")).unwrap();
+  assert_eq!(served_by.trim(), daemon.id().to_string());
+  assert_eq!(failed.status.code(), Some(1));
+}
+
+#[test]
+fn a_client_hanging_up_cancels_its_run() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      slow:
+        steps:
+          - run: sleep 1 && touch late
+      quick:
+        steps:
+          - run: touch quick
+"#,
+  );
+  let (mut daemon, _) = start_daemon(&fixture);
+
+  let mut slow = client(&fixture, "slow")
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()
+    .unwrap();
+  thread::sleep(Duration::from_millis(300));
+  slow.kill().unwrap();
+  slow.wait().unwrap();
+  // The daemon only takes the next request once the cancelled one is done.
+  let quick = client(&fixture, "quick").output().unwrap();
+  thread::sleep(Duration::from_millis(1500));
+  daemon.kill().unwrap();
+  daemon.wait().unwrap();
+
+  assert!(quick.status.success());
+  assert!(fixture.path("quick").exists());
+  assert!(!fixture.path("late").exists());
+}
@@ -0,0 +1,239 @@
+mod common;
+
+use std::fs;
+
+use common::Fixture;
+use procon::{instance::Instance, run::RunOptions};
+
+#[test]
+fn project_env_reaches_commands_and_steps_override_it() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  web:
+    dir: $ROOT/web
+    env: { NODE_ENV: production, TIER: web }
+    phases:
+      build:
+        steps:
+          - run: echo $NODE_ENV > node_env
+          - run: echo $TIER > tier
+            env: { TIER: edge }
+"#,
+  );
+  let web = fixture.mkdir("web");
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert_eq!(
+    fs::read_to_string(web.join("node_env")).unwrap(),
+    "production\n"
+  );
+  assert_eq!(fs::read_to_string(web.join("tier")).unwrap(), "edge\n");
+}
+
+#[test]
+fn env_file_is_loaded_under_project_env() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  api:
+    dir: $ROOT/api
+    env_file: .env
+    env: { MODE: live }
+    phases:
+      build:
+        steps:
+          - run: echo $FOO $GREETING $MODE > out
+"#,
+  );
+  let api = fixture.mkdir("api");
+  fs::write(
+    api.join(".env"),
+    "# secrets\nFOO=bar\n\nGREETING=\"hello there\"\nMODE=test\n",
+  )
+  .unwrap();
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert_eq!(
+    fs::read_to_string(api.join("out")).unwrap(),
+    "bar hello there live\n"
+  );
+}
+
+#[test]
+fn awkward_dirs_and_values_reach_commands_unchanged() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: "$ROOT/it's a dir"
+    env: { MSG: "costs $5\n'quoted'\tdone" }
+    phases:
+      build:
+        steps:
+          - run: printf %s "$PROJECT_DIR" > dir && printf %s "$MSG" > msg
+"#,
+  );
+  let dir = fixture.mkdir("it's a dir");
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert_eq!(
+    fs::read_to_string(dir.join("dir")).unwrap(),
+    fs::canonicalize(&dir).unwrap().to_string_lossy()
+  );
+  assert_eq!(
+    fs::read_to_string(dir.join("msg")).unwrap(),
+    "costs $5\n'quoted'\tdone"
+  );
+}
+
+#[test]
+fn print_command_shows_the_env_a_run_gets() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  api:
+    dir: $ROOT/api
+    env_file: .env
+    env: { MODE: live }
+    phases:
+      build:
+        steps:
+          - run: make
+            env: { STEP: "1" }
+"#,
+  );
+  let api = fixture.mkdir("api");
+  fs::write(api.join(".env"), "FOO=bar\nMODE=test\n").unwrap();
+
+  let output = std::process::Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["print-command", "api", "build", "1"])
+    .output()
+    .unwrap();
+
+  assert!(output.status.success());
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  let env: Vec<_> = stdout
+    .lines()
+    .filter(|line| line.starts_with("env: "))
+    .collect();
+  assert_eq!(env, ["env: FOO=bar", "env: MODE=live", "env: STEP=1"]);
+}
+
+#[test]
+fn hook_env_names_are_checked() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        pre:
+          - run: "true"
+            env: { "X; touch pwned; Y": "1" }
+        steps:
+          - run: "true"
+"#,
+  );
+
+  let err = Instance::try_init(fixture.path("procon.yaml")).unwrap_err();
+  assert_eq!(
+    err.to_string(),
+    "invalid environment variable name 'X; touch pwned; Y'"
+  );
+
+  let output = std::process::Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .arg("validate")
+    .output()
+    .unwrap();
+  assert!(!output.status.success());
+  assert!(!fixture.path("pwned").exists());
+}
+
+#[test]
+fn describe_lists_hooks() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    post:
+      - run: echo cleanup
+    phases:
+      build:
+        pre:
+          - run: echo setup
+        steps:
+          - run: make
+"#,
+  );
+
+  let output = std::process::Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["describe", "app"])
+    .output()
+    .unwrap();
+
+  assert!(output.status.success());
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(
+    stdout.contains(
+      "    build:\n      pre step 1:\n        $ echo setup\n      step 1:\n        \
+       $ make\n  post:\n    step 1:\n      $ echo cleanup\n"
+    ),
+    "{stdout}"
+  );
+}
+
+#[test]
+fn global_commands_get_global_env_and_can_clear_the_rest() {
+  let fixture = Fixture::new(
+    r#"
+global_env: { TIER: web, MODE: test }
+global:
+  show:
+    env: { MODE: live }
+    steps:
+      - run: echo "$TIER $MODE ${OUTSIDE:-unset}" > shown
+  bare:
+    env_clear: true
+    steps:
+      - run: echo "$TIER ${OUTSIDE:-unset} $(command -v sh)" > bare
+projects: {}
+"#,
+  );
+
+  let output = std::process::Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "--global", "show", "bare"])
+    .env("OUTSIDE", "here")
+    .output()
+    .unwrap();
+
+  assert!(output.status.success());
+  assert_eq!(
+    fs::read_to_string(fixture.path("shown")).unwrap(),
+    "web live here\n"
+  );
+  let bare = fs::read_to_string(fixture.path("bare")).unwrap();
+  assert!(bare.starts_with("web unset /"), "{bare}");
+}
@@ -0,0 +1,183 @@
+mod common;
+
+use common::Fixture;
+use procon::run::RunOptions;
+
+const CONFIG: &str = r#"
+projects:
+  svc-auth:
+    dir: $ROOT
+    phases:
+      build: { steps: [{ run: touch svc-auth }] }
+  svc-billing:
+    dir: $ROOT
+    phases:
+      build: { steps: [{ run: touch svc-billing }] }
+  web:
+    dir: $ROOT
+    phases:
+      build: { steps: [{ run: touch web }] }
+"#;
+
+fn built(fixture: &Fixture) -> Vec<&'static str> {
+  ["svc-auth", "svc-billing", "web"]
+    .into_iter()
+    .filter(|name| fixture.path(name).exists())
+    .collect()
+}
+
+#[test]
+fn glob_selects_matching_projects() {
+  let fixture = Fixture::new(CONFIG);
+
+  fixture
+    .instance()
+    .cmd_run(
+      vec!["build".into()],
+      Some(vec!["svc-*".into()]),
+      None,
+      &RunOptions::default(),
+    )
+    .unwrap();
+
+  assert_eq!(built(&fixture), ["svc-auth", "svc-billing"]);
+}
+
+#[test]
+fn star_selects_every_project() {
+  let fixture = Fixture::new(CONFIG);
+
+  fixture
+    .instance()
+    .cmd_run(
+      vec!["build".into()],
+      Some(vec!["*".into()]),
+      None,
+      &RunOptions::default(),
+    )
+    .unwrap();
+
+  assert_eq!(built(&fixture), ["svc-auth", "svc-billing", "web"]);
+}
+
+#[test]
+fn exact_names_still_select_one_project() {
+  let fixture = Fixture::new(CONFIG);
+
+  fixture
+    .instance()
+    .cmd_run(
+      vec!["build".into()],
+      Some(vec!["web".into()]),
+      None,
+      &RunOptions::default(),
+    )
+    .unwrap();
+
+  assert_eq!(built(&fixture), ["web"]);
+}
+
+const TAGGED: &str = r#"
+projects:
+  db:
+    dir: $ROOT
+    tags: [data]
+    phases:
+      build: { steps: [{ run: touch db }] }
+  api:
+    dir: $ROOT
+    tags: [web, data]
+    phases:
+      build: { steps: [{ run: touch api }] }
+  site:
+    dir: $ROOT
+    tags: [web]
+    phases:
+      build: { steps: [{ run: touch site }] }
+"#;
+
+fn tagged_built(fixture: &Fixture) -> Vec<&'static str> {
+  ["api", "db", "site"]
+    .into_iter()
+    .filter(|name| fixture.path(name).exists())
+    .collect()
+}
+
+#[test]
+fn tags_select_projects_with_any_of_them() {
+  let fixture = Fixture::new(TAGGED);
+  let opts = RunOptions {
+    tags: vec!["web".into()],
+    ..Default::default()
+  };
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &opts)
+    .unwrap();
+
+  assert_eq!(tagged_built(&fixture), ["api", "site"]);
+}
+
+#[test]
+fn all_tags_selects_projects_with_every_tag() {
+  let fixture = Fixture::new(TAGGED);
+  let opts = RunOptions {
+    tags: vec!["web".into(), "data".into()],
+    all_tags: true,
+    ..Default::default()
+  };
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &opts)
+    .unwrap();
+
+  assert_eq!(tagged_built(&fixture), ["api"]);
+}
+
+#[test]
+fn tags_compose_with_project_names() {
+  let fixture = Fixture::new(TAGGED);
+  let opts = RunOptions {
+    tags: vec!["web".into()],
+    ..Default::default()
+  };
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], Some(vec!["s*".into()]), None, &opts)
+    .unwrap();
+
+  assert_eq!(tagged_built(&fixture), ["site"]);
+}
+
+#[test]
+fn config_defaults_apply_when_nothing_is_given() {
+  let fixture = Fixture::new(&format!(
+    "{CONFIG}default_phases: [build]\ndefault_projects: [web]\n"
+  ));
+
+  fixture
+    .instance()
+    .cmd_run(Vec::new(), None, None, &RunOptions::default())
+    .unwrap();
+
+  assert_eq!(built(&fixture), ["web"]);
+}
+
+#[test]
+fn running_no_phases_lists_the_available_ones() {
+  let fixture = Fixture::new(CONFIG);
+
+  let err = fixture
+    .instance()
+    .cmd_run(Vec::new(), None, None, &RunOptions::default())
+    .unwrap_err();
+
+  assert_eq!(
+    err.to_string(),
+    "no phases given and no default_phases set. Available phases: build"
+  );
+  assert!(built(&fixture).is_empty());
+}
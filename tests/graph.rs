@@ -0,0 +1,101 @@
+mod common;
+
+use common::Fixture;
+
+const CONFIG: &str = r#"
+projects:
+  api:
+    dir: $ROOT
+    needs: [db]
+    phases:
+      build:
+        steps:
+          - task: compile
+          - run: echo done
+      test:
+        steps:
+          - task: compile
+  db:
+    dir: $ROOT
+tasks:
+  compile:
+    steps:
+      - task: fetch
+      - run: make
+  fetch:
+    steps:
+      - run: git fetch
+"#;
+
+#[test]
+fn dot_graph_has_task_calls_and_needs() {
+  let fixture = Fixture::new(CONFIG);
+
+  assert_eq!(
+    fixture.instance().graph(false),
+    r#"digraph procon {
+  "project:api" [label="api", shape=box];
+  "project:db" [label="db", shape=box];
+  "task:compile" [label="compile", shape=ellipse];
+  "task:fetch" [label="fetch", shape=ellipse];
+  "project:api" -> "task:compile";
+  "task:compile" -> "task:fetch";
+  "project:api" -> "project:db" [style=dashed, label="needs"];
+}
+"#
+  );
+}
+
+#[test]
+fn mermaid_graph_numbers_its_nodes() {
+  let fixture = Fixture::new(CONFIG);
+
+  assert_eq!(
+    fixture.instance().graph(true),
+    r#"flowchart LR
+  p0["api"]
+  p1["db"]
+  t0(["compile"])
+  t1(["fetch"])
+  p0 --> t0
+  t0 --> t1
+  p0 -. needs .-> p1
+"#
+  );
+}
+
+#[test]
+fn graph_includes_tasks_called_from_hooks() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    pre:
+      - task: fetch
+    phases:
+      build:
+        steps:
+          - run: make
+        post:
+          - task: notify
+tasks:
+  fetch:
+    steps:
+      - run: git fetch
+  notify:
+    steps:
+      - run: echo done
+"#,
+  );
+
+  let graph = fixture.instance().graph(false);
+  assert!(
+    graph.contains(r#""project:app" -> "task:fetch";"#),
+    "{graph}"
+  );
+  assert!(
+    graph.contains(r#""project:app" -> "task:notify";"#),
+    "{graph}"
+  );
+}
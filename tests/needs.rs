@@ -0,0 +1,220 @@
+mod common;
+
+use common::Fixture;
+use procon::run::RunOptions;
+
+#[test]
+fn needs_cycle_is_a_config_error() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  api:
+    dir: $ROOT
+    needs: [web]
+  web:
+    dir: $ROOT
+    needs: [api]
+"#,
+  );
+
+  let opts = RunOptions {
+    dry_run: true,
+    ..Default::default()
+  };
+  let err = fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &opts)
+    .unwrap_err();
+
+  assert_eq!(err.exit_code(), 2);
+  assert_eq!(
+    err.to_string(),
+    "project dependency cycle: api -> web -> api"
+  );
+}
+
+#[test]
+fn unknown_need_is_a_config_error() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  web:
+    dir: $ROOT
+    needs: [api]
+"#,
+  );
+
+  let err = fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap_err();
+
+  assert_eq!(err.to_string(), "project 'web' needs unknown project 'api'");
+}
+
+#[test]
+fn projects_run_after_the_ones_they_need() {
+  // `app` sorts first, but needs `zlib`.
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    needs: [zlib]
+    phases:
+      build:
+        steps:
+          - run: test -f zlib && touch app
+  zlib:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: touch zlib
+"#,
+  );
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert!(fixture.path("app").exists());
+}
+
+#[test]
+fn dependents_of_a_failed_project_are_skipped() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  api:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: "false"
+  web:
+    dir: $ROOT
+    needs: [api]
+    phases:
+      build:
+        steps:
+          - run: touch web
+  site:
+    dir: $ROOT
+    needs: [web]
+    phases:
+      build:
+        steps:
+          - run: touch site
+  docs:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: touch docs
+"#,
+  );
+
+  let opts = RunOptions {
+    keep_going: true,
+    ..Default::default()
+  };
+  let err = fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &opts)
+    .unwrap_err();
+
+  assert_eq!(err.to_string(), "failed projects: api");
+  assert!(!fixture.path("web").exists());
+  assert!(!fixture.path("site").exists());
+  assert!(fixture.path("docs").exists());
+}
+
+#[test]
+fn dependents_of_a_disabled_project_are_skipped() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  api:
+    dir: $ROOT
+    enabled: false
+  web:
+    dir: $ROOT
+    needs: [api]
+    phases:
+      build:
+        steps:
+          - run: touch web
+"#,
+  );
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert!(!fixture.path("web").exists());
+}
+
+const DEPLOY: &str = r#"
+projects:
+  api:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: touch api-built
+  web:
+    dir: $ROOT
+    needs: [api]
+    phases:
+      build:
+        steps:
+          - run: touch web-built
+      deploy:
+        steps:
+          - run: touch web-deployed
+"#;
+
+#[test]
+fn selected_projects_run_without_their_needs_by_default() {
+  let fixture = Fixture::new(DEPLOY);
+
+  fixture
+    .instance()
+    .cmd_run(
+      vec!["build".into()],
+      Some(vec!["web".into()]),
+      None,
+      &RunOptions::default(),
+    )
+    .unwrap();
+
+  assert!(fixture.path("web-built").exists());
+  assert!(!fixture.path("api-built").exists());
+}
+
+#[test]
+fn with_needs_adds_needed_projects_and_skips_those_without_the_phase() {
+  let fixture = Fixture::new(DEPLOY);
+  let opts = RunOptions {
+    with_needs: true,
+    fail_on_warning: true,
+    ..Default::default()
+  };
+
+  fixture
+    .instance()
+    .cmd_run(
+      vec!["build".into(), "deploy".into()],
+      Some(vec!["web".into()]),
+      None,
+      &opts,
+    )
+    .unwrap();
+
+  assert!(fixture.path("api-built").exists());
+  assert!(fixture.path("web-built").exists());
+  assert!(fixture.path("web-deployed").exists());
+}
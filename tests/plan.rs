@@ -0,0 +1,508 @@
+mod common;
+
+use std::fs;
+
+use common::Fixture;
+use procon::{instance::Instance, run::RunOptions};
+
+#[test]
+fn plan_substitutes_task_and_project_args() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    with: { target: release }
+    phases:
+      build:
+        steps:
+          - run: cargo build --{{target}}
+          - task: greet
+            with: { who: world }
+tasks:
+  greet:
+    args: [who]
+    steps:
+      - run: echo hello {{who}} from {{target}}
+"#,
+  );
+  fixture.mkdir("app");
+
+  let plan = fixture.instance().plan_phase("app", "build").unwrap();
+  assert_eq!(
+    plan,
+    ["cargo build --release", "echo hello world from release"]
+  );
+}
+
+#[test]
+fn plan_passes_args_through_nested_tasks() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - task: outer
+            with: { version: "1.2" }
+tasks:
+  outer:
+    args: [version]
+    steps:
+      - task: inner
+  inner:
+    args: [version]
+    steps:
+      - run: echo {{version}}
+"#,
+  );
+
+  let plan = fixture.instance().plan_phase("app", "build").unwrap();
+  assert_eq!(plan, ["echo 1.2"]);
+}
+
+#[test]
+fn plan_uses_placeholders_for_captures() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    phases:
+      build:
+        steps:
+          - run: git describe
+            capture: VERSION
+          - run: echo {{VERSION}}
+"#,
+  );
+  fixture.mkdir("app");
+
+  let plan = fixture.instance().plan_phase("app", "build").unwrap();
+  assert_eq!(plan, ["git describe", "echo <VERSION>"]);
+}
+
+#[test]
+fn plan_reports_unknown_phase() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+"#,
+  );
+
+  let err = fixture.instance().plan_phase("app", "build").unwrap_err();
+  assert_eq!(err.exit_code(), 3);
+}
+
+#[test]
+fn dry_run_runs_nothing() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    phases:
+      build:
+        steps:
+          - run: touch marker
+"#,
+  );
+  let app = fixture.mkdir("app");
+
+  let opts = RunOptions {
+    dry_run: true,
+    ..Default::default()
+  };
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &opts)
+    .unwrap();
+
+  assert!(!app.join("marker").exists());
+}
+
+#[test]
+fn plan_joins_list_args() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    phases:
+      build:
+        steps:
+          - task: build
+            with: { targets: [a, b, c] }
+tasks:
+  build:
+    args: [targets]
+    steps:
+      - run: make {{targets}}
+"#,
+  );
+  fixture.mkdir("app");
+
+  let plan = fixture.instance().plan_phase("app", "build").unwrap();
+  assert_eq!(plan, ["make a b c"]);
+}
+
+#[test]
+fn project_with_joins_list_args_too() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    with: { targets: [a, b] }
+    phases:
+      build:
+        steps:
+          - task: build
+tasks:
+  build:
+    args: [targets]
+    steps:
+      - run: make {{targets}}
+"#,
+  );
+  fixture.mkdir("app");
+
+  let plan = fixture.instance().plan_phase("app", "build").unwrap();
+  assert_eq!(plan, ["make a b"]);
+}
+
+#[test]
+fn matrix_expands_into_projects() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  deploy:
+    dir: $ROOT
+    matrix: { region: [us, eu], tier: [web] }
+    phases:
+      ship:
+        steps:
+          - run: ship --region {{region}} --tier {{tier}}
+"#,
+  );
+  let instance = fixture.instance();
+
+  assert_eq!(
+    instance.plan_phase("deploy-us-web", "ship").unwrap(),
+    ["ship --region us --tier web"]
+  );
+  assert_eq!(
+    instance.plan_phase("deploy-eu-web", "ship").unwrap(),
+    ["ship --region eu --tier web"]
+  );
+  assert!(instance.plan_phase("deploy", "ship").is_err());
+}
+
+#[test]
+fn matrix_values_reach_the_env_and_needs_cover_every_instance() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  deploy:
+    dir: $ROOT
+    matrix: { region: [us, eu] }
+    phases:
+      ship:
+        steps:
+          - run: echo deploy $region >> log
+  smoke:
+    dir: $ROOT
+    needs: [deploy]
+    phases:
+      ship:
+        steps:
+          - run: echo smoke >> log
+"#,
+  );
+
+  fixture
+    .instance()
+    .cmd_run(vec!["ship".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert_eq!(
+    fs::read_to_string(fixture.path("log")).unwrap(),
+    "deploy eu\ndeploy us\nsmoke\n"
+  );
+}
+
+#[test]
+fn matrix_expansions_must_not_replace_projects() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  deploy:
+    dir: $ROOT
+    matrix: { region: [us, eu] }
+  deploy-us:
+    dir: $ROOT
+"#,
+  );
+
+  let err = Instance::try_init(fixture.path("procon.yaml")).unwrap_err();
+
+  assert_eq!(
+    err.to_string(),
+    "the matrix of project 'deploy' expands to 'deploy-us', which already \
+     exists"
+  );
+  assert_eq!(err.exit_code(), 2);
+}
+
+#[test]
+fn matrix_keys_need_values() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  deploy:
+    dir: $ROOT
+    matrix: { region: [] }
+"#,
+  );
+
+  let err = Instance::try_init(fixture.path("procon.yaml")).unwrap_err();
+
+  assert_eq!(
+    err.to_string(),
+    "the matrix of project 'deploy' has no values for 'region'"
+  );
+  assert_eq!(err.exit_code(), 2);
+}
+
+#[test]
+fn plan_fails_fast_on_task_cycles() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - task: a
+tasks:
+  a:
+    steps:
+      - task: b
+  b:
+    steps:
+      - task: a
+"#,
+  );
+
+  let err = fixture.instance().plan_phase("app", "build").unwrap_err();
+  assert_eq!(err.to_string(), "task cycle: a -> b -> a");
+  assert_eq!(err.exit_code(), 2);
+}
+
+#[test]
+fn plan_reports_unknown_tasks() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - task: nope
+"#,
+  );
+
+  let err = fixture.instance().plan_phase("app", "build").unwrap_err();
+  assert_eq!(err.to_string(), "task 'nope' not found");
+  assert_eq!(err.exit_code(), 2);
+}
+
+#[test]
+fn steps_may_have_no_commands() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    phases:
+      build:
+        steps:
+          - run: []
+          - run: ~
+            env: { WARM: "1" }
+          - run: touch built
+"#,
+  );
+  let app = fixture.mkdir("app");
+  let instance = fixture.instance();
+
+  assert_eq!(
+    instance.plan_phase("app", "build").unwrap(),
+    ["touch built"]
+  );
+  instance
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+  assert!(app.join("built").exists());
+}
+
+#[test]
+fn plan_includes_hooks_in_run_order() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    pre:
+      - run: echo project pre
+    post:
+      - run: echo project post
+    phases:
+      build:
+        pre:
+          - run: echo phase pre
+        steps:
+          - run: echo build
+        post:
+          - run: echo phase post
+"#,
+  );
+
+  let plan = fixture.instance().plan_phase("app", "build").unwrap();
+  assert_eq!(
+    plan,
+    [
+      "echo project pre",
+      "echo phase pre",
+      "echo build",
+      "echo phase post",
+      "echo project post",
+    ]
+  );
+
+  let output = std::process::Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build", "--dry-run", "--format", "json"])
+    .output()
+    .unwrap();
+  assert!(output.status.success());
+  let entries: Vec<serde_json::Value> =
+    serde_json::from_slice(&output.stdout).unwrap();
+  let steps: Vec<_> = entries
+    .iter()
+    .map(|entry| (entry["step"].clone(), entry["command"].clone()))
+    .collect();
+  assert_eq!(
+    steps,
+    [
+      (0.into(), "echo project pre".into()),
+      (0.into(), "echo phase pre".into()),
+      (1.into(), "echo build".into()),
+      (0.into(), "echo phase post".into()),
+      (0.into(), "echo project post".into()),
+    ]
+  );
+}
+
+#[test]
+fn explain_deps_lists_hooks() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    deps: [git]
+    phases:
+      build:
+        pre:
+          - run: echo pre
+            deps: [jq]
+        steps:
+          - run: echo build
+"#,
+  );
+
+  let output = std::process::Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build", "--explain-deps"])
+    .output()
+    .unwrap();
+
+  assert!(output.status.success());
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(
+    stdout.contains("  hook: git jq\n  step 1: git\n"),
+    "{stdout}"
+  );
+}
+
+#[test]
+fn dry_run_shows_the_limits_wrapper() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: make
+            limits: { memory: 512M }
+"#,
+  );
+
+  let output = std::process::Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build", "--dry-run"])
+    .output()
+    .unwrap();
+
+  assert!(output.status.success());
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(
+    stdout.contains(
+      "  as: 'systemd-run' '--user' '--scope' '--quiet' '-p' \
+       'MemoryMax=512M' '--' "
+    ),
+    "{stdout}"
+  );
+}
+
+#[test]
+fn dry_run_shows_the_container_invocation() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: make
+            container: alpine
+"#,
+  );
+
+  let output = std::process::Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build", "--dry-run"])
+    .output()
+    .unwrap();
+
+  assert!(output.status.success());
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(stdout.contains("would run: make\n"), "{stdout}");
+  assert!(stdout.contains("'run' '--rm' "), "{stdout}");
+  assert!(stdout.contains(" '-e' 'PROJECT_NAME=app' "), "{stdout}");
+  assert!(stdout.contains(" 'alpine' 'sh' '-c' 'make'\n"), "{stdout}");
+}
@@ -0,0 +1,1376 @@
+mod common;
+
+use std::{
+  fs,
+  os::unix::fs::PermissionsExt,
+  path::Path,
+  process::Command,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use common::Fixture;
+use procon::{
+  ShellContext,
+  config::Cmds,
+  events::{Emitter, Event},
+  resolve_nix_shell,
+  run::{
+    CancellationToken, CommandLog, RunOptions, Status, execute,
+    execute_captured,
+  },
+  shell_script,
+};
+
+#[test]
+fn captured_output_is_returned_on_success() {
+  let mut command = Command::new("sh");
+  command.args(["-c", "echo hello"]);
+
+  let (status, stdout) =
+    execute_captured(&mut command, &RunOptions::default()).unwrap();
+
+  assert_eq!(status, Status::Success);
+  assert_eq!(String::from_utf8_lossy(&stdout), "hello\n");
+}
+
+#[test]
+fn commands_are_killed_after_their_timeout() {
+  // The shell's own children must go too: a `sleep` left behind would keep
+  // the piped output open and touch the file later.
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: sleep 2; touch late
+            timeout: 500ms
+"#,
+  );
+  let opts = RunOptions {
+    quiet_on_success: true,
+    ..Default::default()
+  };
+
+  let start = Instant::now();
+  let err = fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &opts)
+    .unwrap_err();
+
+  assert_eq!(err.exit_code(), 1);
+  assert!(start.elapsed() < Duration::from_millis(1500));
+  std::thread::sleep(Duration::from_secs(2));
+  assert!(!fixture.path("late").exists());
+}
+
+#[test]
+fn retries_wait_for_their_delay() {
+  let mut command = Command::new("false");
+  let opts = RunOptions {
+    retries: 1,
+    retry_delay: Some(Duration::from_millis(500)),
+    ..Default::default()
+  };
+
+  let start = Instant::now();
+  let status = execute(&mut command, &opts).unwrap();
+
+  assert_eq!(status, Status::Failed);
+  assert!(start.elapsed() >= Duration::from_millis(500));
+}
+
+#[test]
+fn keep_going_runs_the_steps_after_a_failure() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    phases:
+      build:
+        steps:
+          - run: "false"
+          - run: touch built
+      test:
+        steps:
+          - run: touch tested
+"#,
+  );
+  let app = fixture.mkdir("app");
+  let opts = RunOptions {
+    keep_going: true,
+    ..Default::default()
+  };
+
+  let err = fixture
+    .instance()
+    .cmd_run(vec!["build".into(), "test".into()], None, None, &opts)
+    .unwrap_err();
+
+  assert_eq!(err.to_string(), "failed projects: app");
+  assert!(app.join("built").exists());
+  assert!(app.join("tested").exists());
+}
+
+#[test]
+fn failed_projects_fail_the_run_after_the_others_finish() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    phases:
+      build:
+        steps:
+          - run: "false"
+  lib:
+    dir: $ROOT/lib
+    phases:
+      build:
+        steps:
+          - run: touch built
+"#,
+  );
+  fixture.mkdir("app");
+  let lib = fixture.mkdir("lib");
+
+  let err = fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap_err();
+
+  assert_eq!(err.to_string(), "failed projects: app");
+  assert_eq!(err.exit_code(), 1);
+  assert!(lib.join("built").exists());
+}
+
+#[test]
+fn commands_that_cannot_start_fail_the_run() {
+  // The flake's nix is missing here, or else can't find the flake, so the
+  // first step fails either way.
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    nix: { mode: flake, flake: "path:$ROOT/missing" }
+    phases:
+      build:
+        steps:
+          - run: touch built
+          - run: touch after-build
+      probe:
+        steps:
+          - run: touch probed
+            capture: out
+          - run: touch after-probe
+"#,
+  );
+  let app = fixture.mkdir("app");
+
+  for phase in ["build", "probe"] {
+    let err = fixture
+      .instance()
+      .cmd_run(vec![phase.into()], None, None, &RunOptions::default())
+      .unwrap_err();
+    assert_eq!(err.exit_code(), 1);
+  }
+
+  assert_eq!(std::fs::read_dir(&app).unwrap().count(), 0);
+}
+
+#[test]
+fn commands_run_in_the_project_shell() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    shell: sh
+    phases:
+      build:
+        steps:
+          - run: echo $0 > shell
+"#,
+  );
+  let app = fixture.mkdir("app");
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert_eq!(fs::read_to_string(app.join("shell")).unwrap(), "sh\n");
+}
+
+#[test]
+fn a_failing_command_stops_the_chain() {
+  let fixture = Fixture::new("projects: {}");
+  let app = fixture.mkdir("app");
+  let cmds = Cmds::Many(vec!["false | true".into(), "touch after".into()]);
+
+  let mut command = cmds.assemble(
+    &app,
+    None::<std::slice::Iter<String>>,
+    false,
+    &ShellContext::default(),
+  );
+  let status = execute(&mut command, &RunOptions::default()).unwrap();
+
+  assert_eq!(status, Status::Failed);
+  assert!(!app.join("after").exists());
+}
+
+#[test]
+fn shell_script_sets_the_context_before_the_commands() {
+  let mut ctx = ShellContext {
+    project_name: "app".into(),
+    project_dir: "/srv/app".into(),
+    env: [("MODE".to_string(), "it's".to_string())].into(),
+    ..Default::default()
+  };
+  let cmds = ["make".to_string(), "make test".to_string()];
+
+  assert_eq!(
+    shell_script(&cmds, &ctx),
+    "PROJECT_NAME='app' PROJECT_DIR='/srv/app'; export MODE='it'\\''s'; \
+     make && make test"
+  );
+
+  ctx.wrapper = Some("nice".into());
+  let script = shell_script(&cmds, &ctx);
+  assert!(
+    script.starts_with("nice bash -c 'set -e; set -o pipefail; PROJECT_NAME="),
+    "{script}"
+  );
+  assert!(script.ends_with("; make && make test'"), "{script}");
+}
+
+#[test]
+fn wrapped_steps_can_use_builtins_and_assignments() {
+  let fixture = Fixture::new(
+    r#"
+wrapper: nice -n 5
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: cd sub && touch built
+          - run: MARK=yes sh -c 'echo $MARK' > marked
+"#,
+  );
+  fixture.mkdir("sub");
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert!(fixture.path("sub/built").exists());
+  assert_eq!(fs::read_to_string(fixture.path("marked")).unwrap(), "yes\n");
+}
+
+#[test]
+fn a_cancelled_run_fails_with_its_own_error() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  a:
+    dir: $ROOT
+    phases:
+      build: { steps: [{ run: touch a }] }
+  b:
+    dir: $ROOT
+    phases:
+      build: { steps: [{ run: touch b }] }
+"#,
+  );
+  let cancel = CancellationToken::new();
+  cancel.cancel();
+  let opts = RunOptions {
+    cancel,
+    ..Default::default()
+  };
+
+  let err = fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &opts)
+    .unwrap_err();
+
+  assert_eq!(err.to_string(), "run cancelled");
+  assert_eq!(err.exit_code(), 130);
+  assert!(!fixture.path("a").exists());
+  assert!(!fixture.path("b").exists());
+}
+
+#[test]
+fn strict_substitution_checks_hooks() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    post:
+      - run: echo {{missing}}
+    phases:
+      build: { steps: [{ run: touch built }] }
+"#,
+  );
+  let strict = RunOptions {
+    strict_substitution: true,
+    ..Default::default()
+  };
+
+  let err = fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &strict)
+    .unwrap_err();
+
+  assert_eq!(err.to_string(), "1 unresolved substitution(s)");
+  assert!(!fixture.path("built").exists());
+}
+
+#[test]
+fn fail_on_warning_fails_projects_without_the_phase() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build: { steps: [{ run: touch built }] }
+  docs:
+    dir: $ROOT
+    phases: {}
+"#,
+  );
+  let strict = RunOptions {
+    fail_on_warning: true,
+    ..Default::default()
+  };
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+  let err = fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &strict)
+    .unwrap_err();
+
+  assert_eq!(
+    err.to_string(),
+    "1 warning(s) treated as errors by --fail-on-warning"
+  );
+  assert!(fixture.path("built").exists());
+}
+
+#[test]
+fn per_project_runs_each_projects_phases_together() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  a:
+    dir: $ROOT
+    phases:
+      build: { steps: [{ run: echo a-build >> log }] }
+      start: { steps: [{ run: echo a-start >> log }] }
+  b:
+    dir: $ROOT
+    phases:
+      build: { steps: [{ run: echo b-build >> log }] }
+      start: { steps: [{ run: echo b-start >> log }] }
+"#,
+  );
+  let opts = RunOptions {
+    per_project: true,
+    ..Default::default()
+  };
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into(), "start".into()], None, None, &opts)
+    .unwrap();
+
+  assert_eq!(
+    fs::read_to_string(fixture.path("log")).unwrap(),
+    "a-build\na-start\nb-build\nb-start\n"
+  );
+}
+
+#[test]
+fn if_and_unless_guard_steps() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    phases:
+      setup:
+        steps:
+          - run: touch installed
+            unless: test -d node_modules
+          - run: touch skipped
+            if: test -d node_modules
+          - run: touch checked
+            if: test -f installed
+"#,
+  );
+  let app = fixture.mkdir("app");
+
+  fixture
+    .instance()
+    .cmd_run(vec!["setup".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert!(app.join("installed").exists());
+  assert!(!app.join("skipped").exists());
+  assert!(app.join("checked").exists());
+}
+
+#[test]
+fn nix_shell_path_can_be_overridden() {
+  assert_eq!(
+    resolve_nix_shell(Some("/opt/nix/bin/nix-shell".into())),
+    Path::new("/opt/nix/bin/nix-shell")
+  );
+  assert_eq!(
+    resolve_nix_shell(None),
+    Path::new("/nix/var/nix/profiles/default/bin/nix-shell")
+  );
+  assert_eq!(resolve_nix_shell(Some("".into())), resolve_nix_shell(None));
+}
+
+#[test]
+fn no_nix_fails_steps_with_deps_and_runs_the_rest() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT/app
+    phases:
+      build:
+        steps:
+          - run: touch plain
+      lint:
+        steps:
+          - run: touch linted
+            deps: [shellcheck]
+"#,
+  );
+  let app = fixture.mkdir("app");
+  let opts = RunOptions {
+    no_nix: true,
+    ..Default::default()
+  };
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &opts)
+    .unwrap();
+  let err = fixture
+    .instance()
+    .cmd_run(vec!["lint".into()], None, None, &opts)
+    .unwrap_err();
+
+  assert!(app.join("plain").exists());
+  assert!(!app.join("linted").exists());
+  assert_eq!(err.exit_code(), 1);
+}
+
+#[test]
+fn flake_projects_run_in_nix_develop() {
+  let ctx = ShellContext {
+    flake: Some(".#devShell".into()),
+    ..Default::default()
+  };
+
+  let command = Cmds::Single("make".into()).assemble(
+    &"/srv/app".into(),
+    None::<std::slice::Iter<String>>,
+    false,
+    &ctx,
+  );
+
+  assert!(command.get_program().to_string_lossy().ends_with("/nix"));
+  let args: Vec<_> = command.get_args().collect();
+  assert_eq!(
+    args[..5],
+    ["develop", ".#devShell", "--command", "bash", "-c"]
+  );
+  assert!(args[5].to_string_lossy().ends_with("; make"));
+}
+
+#[test]
+fn pure_is_passed_to_nix_shell_only_when_enabled() {
+  let deps = ["hello".to_string()];
+  let args = |pure| {
+    let ctx = ShellContext {
+      pure,
+      ..Default::default()
+    };
+    Cmds::Single("hello".into())
+      .assemble(&"/srv/app".into(), Some(deps.iter()), false, &ctx)
+      .get_args()
+      .map(|arg| arg.to_string_lossy().into_owned())
+      .collect::<Vec<_>>()
+  };
+
+  assert_eq!(args(true)[..3], ["--pure", "-p", "hello"]);
+  assert!(!args(false).contains(&"--pure".to_string()));
+}
+
+#[test]
+fn run_project_phase_returns_each_commands_output() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: echo building
+          - argv: [echo, linking]
+            shell: false
+          - run: [echo partial, "false", echo never]
+"#,
+  );
+
+  let outcome = fixture
+    .instance()
+    .run_project_phase("app", "build")
+    .unwrap();
+
+  assert_eq!(outcome.status, Status::Failed);
+  let commands: Vec<_> = outcome
+    .commands
+    .iter()
+    .map(|c| (c.step, c.command.as_str(), c.status, c.output.as_str()))
+    .collect();
+  assert_eq!(
+    commands,
+    [
+      (1, "echo building", Status::Success, "building\n"),
+      (2, "'echo' 'linking'", Status::Success, "linking\n"),
+      (3, "echo partial", Status::Success, "partial\n"),
+      (3, "false", Status::Failed, ""),
+    ]
+  );
+}
+
+#[test]
+fn captured_output_reaches_later_steps() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      release:
+        steps:
+          - run: echo 1.2.3
+            capture: VERSION
+          - run: echo "v{{VERSION}}" > tag
+"#,
+  );
+
+  fixture
+    .instance()
+    .cmd_run(vec!["release".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert_eq!(fs::read_to_string(fixture.path("tag")).unwrap(), "v1.2.3\n");
+}
+
+#[test]
+fn global_commands_take_args() {
+  let fixture = Fixture::new(
+    r#"
+global:
+  deploy:
+    - run: echo {{env}} > target
+"#,
+  );
+
+  let args = [("env".to_string(), "prod".to_string())].into();
+  fixture
+    .instance()
+    .cmd_run_global(vec!["deploy".into()], args, &RunOptions::default())
+    .unwrap();
+
+  assert_eq!(
+    fs::read_to_string(fixture.path("target")).unwrap(),
+    "prod\n"
+  );
+}
+
+#[test]
+fn post_hooks_run_after_a_failed_phase() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    pre:
+      - run: echo project-pre >> log
+    post:
+      - run: echo project-post $PROCON_PHASE_STATUS >> log
+    phases:
+      build:
+        pre:
+          - run: echo phase-pre >> log
+        steps:
+          - run: echo build >> log
+          - run: "false"
+          - run: echo never >> log
+        post:
+          - run: echo phase-post $PROCON_PHASE_STATUS >> log
+"#,
+  );
+
+  let err = fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap_err();
+
+  assert_eq!(err.exit_code(), 1);
+  assert_eq!(
+    fs::read_to_string(fixture.path("log")).unwrap(),
+    "project-pre\nphase-pre\nbuild\nphase-post failed\nproject-post failed\n"
+  );
+}
+
+#[test]
+fn a_failed_pre_hook_skips_the_steps_but_not_post() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        pre:
+          - run: "false"
+        steps:
+          - run: echo build >> log
+        post:
+          - run: echo post $PROCON_PHASE_STATUS >> log
+"#,
+  );
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap_err();
+
+  assert_eq!(
+    fs::read_to_string(fixture.path("log")).unwrap(),
+    "post failed\n"
+  );
+}
+
+#[test]
+fn log_file_records_each_command_and_its_output() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: echo hello
+          - run: echo oops >&2; exit 3
+"#,
+  );
+  let path = fixture.path("procon.log");
+  let opts = RunOptions {
+    log: Some(CommandLog::open(&path).unwrap()),
+    ..Default::default()
+  };
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &opts)
+    .unwrap_err();
+
+  let log = fs::read_to_string(&path).unwrap();
+  let headers: Vec<_> = log
+    .lines()
+    .filter(|line| line.starts_with('['))
+    .map(|line| line.split_once("] ").unwrap().1)
+    .collect();
+  assert_eq!(
+    headers,
+    ["app/build step 1: exit 0", "app/build step 2: exit 3"]
+  );
+  assert!(log.contains("$ echo hello\nstdout:\n  hello\n"));
+  assert!(log.contains("stderr:\n  oops\n"));
+}
+
+#[test]
+fn log_file_and_manifest_resolve_against_the_config_dir() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: echo hello
+"#,
+  );
+  let elsewhere = tempfile::TempDir::new().unwrap();
+
+  let output = Command::new(env!("CARGO_BIN_EXE_procon"))
+    .current_dir(elsewhere.path())
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build", "--log-file", "procon.log"])
+    .args(["--manifest", "manifest.json"])
+    .output()
+    .unwrap();
+
+  assert!(output.status.success());
+  assert!(fixture.path("procon.log").exists());
+  assert!(fixture.path("manifest.json").exists());
+  assert!(!elsewhere.path().join("procon.log").exists());
+}
+
+/// Keeps every event as JSON, without its durations.
+#[derive(Debug, Default)]
+struct Recorder(Mutex<Vec<serde_json::Value>>);
+
+impl Emitter for Recorder {
+  fn emit(&self, event: &Event) {
+    let mut value = serde_json::to_value(event).unwrap();
+    value.as_object_mut().unwrap().remove("duration_ms");
+    self.0.lock().unwrap().push(value);
+  }
+}
+
+#[test]
+fn events_report_phases_and_commands() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: "true"
+"#,
+  );
+  let recorder = Arc::new(Recorder::default());
+  let opts = RunOptions {
+    events: Some(recorder.clone()),
+    ..Default::default()
+  };
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &opts)
+    .unwrap();
+
+  let events = recorder.0.lock().unwrap();
+  let kinds: Vec<_> = events.iter().map(|e| e["event"].clone()).collect();
+  assert_eq!(
+    kinds,
+    ["phase_start", "step_start", "step_end", "phase_end"]
+  );
+  assert_eq!(events[1]["project"], "app");
+  assert_eq!(events[1]["step_index"], 1);
+  assert_eq!(events[1]["command"], "true");
+  assert_eq!(events[2]["command"], "true");
+  assert_eq!(events[2]["status"], "success");
+  assert_eq!(events[3]["phase"], "build");
+}
+
+#[test]
+fn the_banner_shows_the_steps_command() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: echo hello
+"#,
+  );
+
+  let output = Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build", "--banner-format", "[{project}] {command}"])
+    .output()
+    .unwrap();
+
+  assert!(output.status.success());
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(stdout.contains("[app] echo hello\n"), "{stdout}");
+}
+
+#[test]
+fn guards_run_in_the_steps_container() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: echo built
+            container: alpine
+            if: test -f only-in-container
+"#,
+  );
+  // Stands in for podman, recording what it was asked to run.
+  let bin = fixture.mkdir("bin");
+  let podman = bin.join("podman");
+  fs::write(
+    &podman,
+    format!(
+      "#!/bin/sh\necho \"$@\" >> {}\n",
+      fixture.path("calls").display()
+    ),
+  )
+  .unwrap();
+  fs::set_permissions(&podman, fs::Permissions::from_mode(0o755)).unwrap();
+  let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap());
+
+  let output = Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build"])
+    .env("PATH", path)
+    .output()
+    .unwrap();
+
+  assert!(output.status.success());
+  let calls = fs::read_to_string(fixture.path("calls")).unwrap();
+  let calls: Vec<_> = calls.lines().collect();
+  assert_eq!(calls.len(), 2, "{calls:?}");
+  assert!(calls[0].ends_with("alpine sh -c test -f only-in-container"));
+  assert!(calls[1].ends_with("alpine sh -c echo built"));
+}
+
+#[test]
+fn persist_env_carries_exports_but_not_pwd() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        persist_env: true
+        steps:
+          - run: export GREETING=hello
+          - run: echo "$GREETING" > $ROOT/greeting
+          - run: echo "$PWD" > $ROOT/pwd
+            cwd: sub
+"#,
+  );
+  let sub = fixture.mkdir("sub");
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert_eq!(
+    fs::read_to_string(fixture.path("greeting")).unwrap(),
+    "hello\n"
+  );
+  let pwd = fs::read_to_string(fixture.path("pwd")).unwrap();
+  assert_eq!(
+    fs::canonicalize(pwd.trim()).unwrap(),
+    fs::canonicalize(sub).unwrap()
+  );
+}
+
+#[test]
+fn persist_env_ignores_files_planted_at_its_path() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        persist_env: true
+        steps:
+          - run: "true"
+"#,
+  );
+  let planted: Vec<_> = (0..64)
+    .map(|n| {
+      std::env::temp_dir()
+        .join(format!("procon-{}-{n}.env", std::process::id()))
+    })
+    .filter(|path| !path.exists())
+    .collect();
+  for path in planted.iter() {
+    let payload = format!("touch {}\n", fixture.path("pwned").display());
+    fs::write(path, payload).unwrap();
+  }
+
+  let result = fixture.instance().cmd_run(
+    vec!["build".into()],
+    None,
+    None,
+    &RunOptions::default(),
+  );
+  for path in planted.iter() {
+    let _ = fs::remove_file(path);
+  }
+
+  result.unwrap();
+  assert!(!fixture.path("pwned").exists());
+}
+
+#[test]
+fn argv_steps_fill_in_their_tokens() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    with: { target: release }
+    phases:
+      build:
+        steps:
+          - run: echo v1
+            capture: VERSION
+          - argv: [echo, "{{PROJECT_NAME}}", "{{target}}", "{{VERSION}}"]
+            shell: false
+"#,
+  );
+
+  let outcome = fixture
+    .instance()
+    .run_project_phase("app", "build")
+    .unwrap();
+
+  assert_eq!(outcome.status, Status::Success);
+  assert_eq!(outcome.commands[1].output, "app release v1\n");
+}
+
+#[test]
+fn argv_steps_can_capture_their_output() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - argv: [echo, v2]
+            shell: false
+            capture: VERSION
+          - run: echo {{VERSION}} > version
+"#,
+  );
+
+  fixture
+    .instance()
+    .cmd_run(vec!["build".into()], None, None, &RunOptions::default())
+    .unwrap();
+
+  assert_eq!(fs::read_to_string(fixture.path("version")).unwrap(), "v2\n");
+}
+
+#[test]
+fn jobs_run_projects_at_the_same_time() {
+  // Each project waits for the other to start, so they only both pass if
+  // they run together.
+  let fixture = Fixture::new(
+    r#"
+projects:
+  a:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: touch a && for i in $(seq 50); do [ -f b ] && exit 0; sleep 0.1; done; exit 1
+  b:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: touch b && for i in $(seq 50); do [ -f a ] && exit 0; sleep 0.1; done; exit 1
+"#,
+  );
+
+  let output = Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build", "--jobs", "2"])
+    .output()
+    .unwrap();
+
+  assert!(output.status.success());
+}
+
+#[test]
+fn jobs_still_wait_for_needed_projects() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    needs: [lib]
+    phases:
+      build:
+        steps:
+          - run: test -f lib-built
+  lib:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: sleep 0.3 && touch lib-built
+"#,
+  );
+
+  let output = Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build", "--jobs", "2"])
+    .output()
+    .unwrap();
+
+  assert!(output.status.success());
+}
+
+#[test]
+fn jobs_print_each_commands_output_in_one_block() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  a:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: echo a1; sleep 0.2; echo a2
+  b:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: echo b1; sleep 0.2; echo b2
+"#,
+  );
+
+  let output = Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build", "--jobs", "2"])
+    .output()
+    .unwrap();
+
+  assert!(output.status.success());
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(
+    stdout.contains("$ echo a1; sleep 0.2; echo a2\na1\na2\n"),
+    "{stdout}"
+  );
+  assert!(
+    stdout.contains("$ echo b1; sleep 0.2; echo b2\nb1\nb2\n"),
+    "{stdout}"
+  );
+}
+
+#[test]
+fn quiet_and_verbose_change_what_is_printed() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    env: { MODE: live }
+    phases:
+      build:
+        steps:
+          - run: echo hello
+"#,
+  );
+  let run = |flag: &str| {
+    let output = Command::new(env!("CARGO_BIN_EXE_procon"))
+      .arg("-f")
+      .arg(fixture.path("procon.yaml"))
+      .args(["run", "build", flag])
+      .output()
+      .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+  };
+
+  let quiet = run("--quiet");
+  let verbose = run("--verbose");
+
+  assert_eq!(quiet, "hello\n");
+  assert!(verbose.contains("env: MODE=live\n"), "{verbose}");
+  assert!(verbose.contains("$ echo hello\nhello\n"), "{verbose}");
+  assert!(verbose.contains("summary:"), "{verbose}");
+}
+
+#[test]
+fn gc_roots_are_kept_per_dep_set_until_clean() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: "true"
+            deps: [jq]
+          - run: "true"
+            deps: [jq]
+          - run: "true"
+            deps: [git]
+"#,
+  );
+  // Stands in for nix-shell: it creates the requested roots and runs the
+  // commands.
+  let nix_shell = fixture.path("nix-shell");
+  fs::write(
+    &nix_shell,
+    "#!/bin/sh\nwhile [ $# -gt 0 ]; do\n  case \"$1\" in\n    --add-root) \
+     touch \"$2\"; shift ;;\n    --run) exec bash -c \"$2\" ;;\n  esac\n  \
+     shift\ndone\n",
+  )
+  .unwrap();
+  fs::set_permissions(&nix_shell, fs::Permissions::from_mode(0o755)).unwrap();
+  let procon = |args: &[&str]| {
+    Command::new(env!("CARGO_BIN_EXE_procon"))
+      .arg("-f")
+      .arg(fixture.path("procon.yaml"))
+      .args(args)
+      .env("PROCON_NIX_SHELL", &nix_shell)
+      .output()
+      .unwrap()
+  };
+  let roots = fixture.path(".procon/gcroots");
+
+  let run = procon(&["run", "build", "--gc-roots"]);
+  let kept = fs::read_dir(&roots).unwrap().count();
+  let clean = procon(&["clean"]);
+
+  assert!(run.status.success());
+  assert_eq!(kept, 2);
+  assert!(clean.status.success());
+  let stdout = String::from_utf8(clean.stdout).unwrap();
+  assert_eq!(stdout, "removed 2 GC root(s).\n");
+  assert!(!roots.exists());
+}
+
+#[test]
+fn allowed_failures_only_warn_unless_escalated() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    allow_failure: [lint]
+    phases:
+      lint:
+        steps:
+          - run: "false"
+"#,
+  );
+  let run = |args: &[&str]| {
+    Command::new(env!("CARGO_BIN_EXE_procon"))
+      .arg("-f")
+      .arg(fixture.path("procon.yaml"))
+      .args(["run", "lint"])
+      .args(args)
+      .output()
+      .unwrap()
+  };
+
+  let allowed = run(&[]);
+  let escalated = run(&["--fail-on-warning"]);
+
+  assert!(allowed.status.success());
+  let stdout = String::from_utf8(allowed.stdout).unwrap();
+  assert!(
+    stdout.contains("warning: phase 'lint' failed for 'app' (allowed)"),
+    "{stdout}"
+  );
+  assert_eq!(escalated.status.code(), Some(1));
+}
+
+#[test]
+fn resume_from_skips_the_projects_before_it() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  a:
+    dir: $ROOT/a
+    phases:
+      build:
+        steps:
+          - run: touch built
+  b:
+    dir: $ROOT/b
+    phases:
+      build:
+        steps:
+          - run: touch built
+  c:
+    dir: $ROOT/c
+    phases:
+      build:
+        steps:
+          - run: touch built
+"#,
+  );
+  for dir in ["a", "b", "c"] {
+    fixture.mkdir(dir);
+  }
+
+  let output = Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build", "--resume-from", "b"])
+    .output()
+    .unwrap();
+
+  assert!(output.status.success());
+  assert!(!fixture.path("a/built").exists());
+  assert!(fixture.path("b/built").exists());
+  assert!(fixture.path("c/built").exists());
+}
+
+#[test]
+fn manifest_records_each_projects_status() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  api:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: "false"
+  web:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: "true"
+"#,
+  );
+  let elsewhere = tempfile::TempDir::new().unwrap();
+
+  let output = Command::new(env!("CARGO_BIN_EXE_procon"))
+    .current_dir(elsewhere.path())
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build", "--manifest"])
+    .output()
+    .unwrap();
+
+  assert_eq!(output.status.code(), Some(1));
+  let manifest =
+    fs::read_to_string(fixture.path("artifacts/manifest.json")).unwrap();
+  let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+  let statuses: Vec<_> = manifest
+    .as_array()
+    .unwrap()
+    .iter()
+    .map(|entry| (entry["project"].clone(), entry["status"].clone()))
+    .collect();
+  assert_eq!(
+    statuses,
+    [
+      ("api".into(), "failed".into()),
+      ("web".into(), "success".into())
+    ]
+  );
+}
+
+#[test]
+fn tail_on_failure_shows_only_the_last_lines_of_failures() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      build:
+        steps:
+          - run: echo fine
+          - run: seq 10 && false
+"#,
+  );
+
+  let output = Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build", "--tail-on-failure", "3"])
+    .output()
+    .unwrap();
+
+  assert_eq!(output.status.code(), Some(1));
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(stdout.contains("\n8\n9\n10\n"), "{stdout}");
+  assert!(!stdout.contains("\n7\n"), "{stdout}");
+  assert!(!stdout.contains("fine\n"), "{stdout}");
+}
+
+#[test]
+fn retry_on_only_retries_the_listed_exit_codes() {
+  let fixture = Fixture::new(
+    r#"
+projects:
+  app:
+    dir: $ROOT
+    phases:
+      flaky:
+        steps:
+          - run: echo try >> flaky-tries; exit 75
+            retries: 2
+            retry_on: [75]
+      broken:
+        steps:
+          - run: echo try >> broken-tries; exit 1
+            retries: 2
+            retry_on: [75]
+"#,
+  );
+
+  let output = Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "flaky", "broken", "--keep-going"])
+    .output()
+    .unwrap();
+
+  assert_eq!(output.status.code(), Some(1));
+  let tries = |name: &str| {
+    fs::read_to_string(fixture.path(name))
+      .unwrap()
+      .lines()
+      .count()
+  };
+  assert_eq!(tries("flaky-tries"), 3);
+  assert_eq!(tries("broken-tries"), 1);
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use std::{fs, process::Output};
+
+use common::Fixture;
+
+const CONFIG: &str = r#"
+projects:
+  app:
+    dir: $ROOT
+    env_file: app.env
+    phases:
+      build:
+        steps:
+          - run: echo run >> runs && test -f pass
+"#;
+
+fn fixture() -> Fixture {
+  let fixture = Fixture::new(CONFIG);
+  fs::write(fixture.path("app.env"), "").unwrap();
+  fixture
+}
+
+fn run_if_changed(fixture: &Fixture) -> Output {
+  std::process::Command::new(env!("CARGO_BIN_EXE_procon"))
+    .arg("-f")
+    .arg(fixture.path("procon.yaml"))
+    .args(["run", "build", "--only-if-config-changed"])
+    .output()
+    .unwrap()
+}
+
+fn runs(fixture: &Fixture) -> usize {
+  fs::read_to_string(fixture.path("runs"))
+    .map(|runs| runs.lines().count())
+    .unwrap_or(0)
+}
+
+#[test]
+fn unchanged_config_skips_the_run() {
+  let fixture = fixture();
+  fs::write(fixture.path("pass"), "").unwrap();
+
+  let first = run_if_changed(&fixture);
+  let second = run_if_changed(&fixture);
+
+  assert!(first.status.success());
+  assert!(second.status.success());
+  let stdout = String::from_utf8(second.stdout).unwrap();
+  assert!(stdout.contains("config unchanged"), "{stdout}");
+  assert_eq!(runs(&fixture), 1);
+}
+
+#[test]
+fn editing_the_config_or_env_file_reruns() {
+  let fixture = fixture();
+  fs::write(fixture.path("pass"), "").unwrap();
+  let config = fixture.path("procon.yaml");
+
+  run_if_changed(&fixture);
+  let mut edited = fs::read_to_string(&config).unwrap();
+  edited.push_str("# edited\n");
+  fs::write(&config, edited).unwrap();
+  run_if_changed(&fixture);
+  fs::write(fixture.path("app.env"), "A=1\n").unwrap();
+  run_if_changed(&fixture);
+  run_if_changed(&fixture);
+
+  assert_eq!(runs(&fixture), 3);
+}
+
+#[test]
+fn failed_runs_are_not_recorded() {
+  let fixture = fixture();
+
+  let failed = run_if_changed(&fixture);
+  fs::write(fixture.path("pass"), "").unwrap();
+  let retried = run_if_changed(&fixture);
+
+  assert_eq!(failed.status.code(), Some(1));
+  assert!(retried.status.success());
+  assert_eq!(runs(&fixture), 2);
+}